@@ -1,19 +1,63 @@
+use std::{env, io::ErrorKind, path::Path, process::Command};
+
+/// Where the module built from `crates/wasi-mobilenet-inference` can be,
+/// depending on whether it was built from its directory or from the
+/// workspace, and on the name of the WASI target.
+const MODULES: &[&str] = &[
+    "target/wasm32-wasi/release/wasi_mobilenet_inference.wasm",
+    "target/wasm32-wasip1/release/wasi_mobilenet_inference.wasm",
+    "crates/wasi-mobilenet-inference/target/wasm32-wasi/release/wasi_mobilenet_inference.wasm",
+];
+const OPTIMIZED_MODULE: &str = "model/optimized-wasi.wasm";
+
 fn main() {
     println!("cargo:rerun-if-changed=crates/wasi-mobilenet-inference/src/lib.rs");
+    println!("cargo:rerun-if-env-changed=WASM_OPT");
+    println!("cargo:rerun-if-env-changed=WASM_OPT_LEVEL");
 
     run_wasm_opt();
 }
 
+/// Optimize the module with `wasm-opt`, from `PATH` or from the `WASM_OPT`
+/// environment variable, at the `WASM_OPT_LEVEL` level (`O` by default, or
+/// for example `O3` or `Oz`).
+///
+/// When `wasm-opt` cannot be run, or fails, the build goes on with a warning,
+/// and the server uses the module already in `model/`.
 fn run_wasm_opt() {
-    let mut cmd = std::process::Command::new("wasm-opt");
-    cmd.stdout(std::process::Stdio::piped());
-    cmd.stderr(std::process::Stdio::piped());
-    cmd.arg(
-        "crates/wasi-mobilenet-inference/target/wasm32-wasi/release/wasi_mobilenet_inference.wasm",
-    )
-    .arg("-O")
-    .arg("-o")
-    .arg("model/optimized-wasi.wasm");
-    cmd.output().unwrap();
-    println!("executed wasm-opt");
+    // Without a build of the module there is nothing to optimize, which is
+    // not worth a warning, since `model/` already contains the module.
+    let module = match MODULES.iter().find(|module| Path::new(module).exists()) {
+        Some(module) => module,
+        None => {
+            println!("skipping wasm-opt, the module was not built");
+            return;
+        }
+    };
+
+    let wasm_opt = env::var("WASM_OPT").unwrap_or_else(|_| "wasm-opt".to_string());
+    let level = env::var("WASM_OPT_LEVEL").unwrap_or_else(|_| "O".to_string());
+    let output = Command::new(&wasm_opt)
+        .arg(module)
+        .arg(format!("-{}", level.trim_start_matches('-')))
+        .arg("-o")
+        .arg(OPTIMIZED_MODULE)
+        .output();
+
+    match output {
+        Ok(output) if output.status.success() => {
+            println!("executed wasm-opt -{}", level.trim_start_matches('-'))
+        }
+        Ok(output) => {
+            println!("cargo:warning={} failed with {}", wasm_opt, output.status);
+            for line in String::from_utf8_lossy(&output.stderr).lines() {
+                println!("cargo:warning={}", line);
+            }
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => println!(
+            "cargo:warning=skipping wasm-opt, {} was not found (install Binaryen, or set WASM_OPT)",
+            wasm_opt
+        ),
+        Err(err) => println!("cargo:warning=cannot run {}: {}", wasm_opt, err),
+    }
 }
@@ -1,4 +1,214 @@
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use image::{imageops::FilterType, RgbImage};
 use tract_tensorflow::prelude::*;
+use tract_tensorflow::tract_hir::infer::Factoid;
+
+/// Returned instead of a class index when the requested crop
+/// is larger than the image.
+const CROP_OUT_OF_BOUNDS: i32 = -1;
+
+/// Returned instead of a class index when the model handle was not
+/// returned by `load_model_from_ptrs`.
+const UNKNOWN_MODEL: i32 = -2;
+
+/// Returned instead of a class index when the image cannot be decoded.
+const DECODE_FAILED: i32 = -3;
+
+/// Returned instead of a class index, or of a model handle, when the model
+/// cannot be loaded or optimized.
+const MODEL_LOAD_FAILED: i32 = -4;
+
+/// Returned instead of a class index when running the model fails, for
+/// example because its input does not have the expected shape.
+const RUN_FAILED: i32 = -5;
+
+/// Returned instead of a class index, or of a model handle, when the width
+/// or the height of the input images of the model is 0.
+const INVALID_DIMENSIONS: i32 = -6;
+
+/// Returned by `infer_batch_with_handle` when the images are not packed
+/// as expected.
+const INVALID_BATCH: i32 = -7;
+
+/// Returned instead of a model handle, or of a class index, when the model
+/// has several inputs and none was selected with `set_input_name`, or when
+/// the selected input is not one of the inputs of the model.
+const UNKNOWN_INPUT: i32 = -8;
+
+/// Returned instead of a class index when the tensor passed to the tensor
+/// inference functions does not have as many values as the input of the
+/// model, or the input of the model is not `f32`.
+const INVALID_TENSOR: i32 = -9;
+
+/// Returned by the label inference functions when the predicted class has
+/// no label, such as when the labels are not the ones of the model.
+const UNKNOWN_LABEL: i32 = -10;
+
+/// Returned instead of a class index when the width or the height of the
+/// image, read from its header, is larger than the maximum set with
+/// `set_max_dimension`, so that the image is not decoded.
+const IMAGE_TOO_LARGE: i32 = -11;
+
+/// Width and height of the input images of the functions that do not take
+/// the dimensions of the model, which are the ones of MobileNet V2.
+const DEFAULT_SIZE: u32 = 224;
+
+/// Maximum number of dimensions of the output written by the raw inference
+/// functions, and size of the header they write at `out_ptr`: the pointer
+/// to the values, the number of dimensions, then each dimension, all as
+/// little-endian `u32`s.
+const MAX_OUTPUT_RANK: usize = 8;
+const RAW_OUTPUT_HEADER: usize = 4 * (2 + MAX_OUTPUT_RANK);
+
+/// A model that was optimized and made runnable.
+type Runnable = TypedRunnableModel<TypedModel>;
+
+/// A model that was loaded, optimized, and made runnable, with the
+/// dimensions of its input images.
+struct Model {
+    runnable: Runnable,
+    width: u32,
+    height: u32,
+    /// Type of the values of the input tensor, either `f32` normalized
+    /// pixels, or `u8` pixels for quantized models.
+    input_type: DatumType,
+    /// The model as it was loaded, from which the variants for batches
+    /// of several images are compiled.
+    source: InferenceModel,
+    /// Variants of the model compiled for batches of several images, with
+    /// their batch size.
+    batches: RefCell<Vec<(usize, Rc<Runnable>)>>,
+}
+
+thread_local! {
+    /// Models compiled by `load_sized_model_from_ptrs`, with the hash of
+    /// their bytes and dimensions. The handle of a model is its index.
+    static MODELS: RefCell<Vec<(u64, Rc<Model>)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Whether the score of the first output of the model, which is the
+/// background class for MobileNet, is excluded from the argmax.
+static SKIP_BACKGROUND: AtomicBool = AtomicBool::new(false);
+
+/// Filter used to resize the images to the input dimensions of the model,
+/// as set by `set_resize_filter`.
+static RESIZE_FILTER: AtomicU32 = AtomicU32::new(1);
+
+/// Maximum width and height of the images that are decoded, as set by
+/// `set_max_dimension`.
+static MAX_DIMENSION: AtomicU32 = AtomicU32::new(10000);
+
+/// Whether the images are center-cropped when resized to the input
+/// dimensions of the model, as set by `set_preprocess`.
+static CENTER_CROP: AtomicBool = AtomicBool::new(false);
+
+/// Ratio of the center crop to the resized image, as in the ImageNet
+/// evaluation of MobileNet, which resizes the shorter side of the images
+/// to 256 pixels before taking their central 224 x 224 pixels.
+const CENTER_CROP_RATIO: f32 = 224.0 / 256.0;
+
+/// How the pixels of the image are normalized before running the model.
+static NORMALIZATION: Mutex<Normalization> = Mutex::new(Normalization::ZeroToOne);
+
+/// Values of the input tensor of a model, computed from the pixels of
+/// the image, which range from 0 to 255.
+#[derive(Clone, Copy)]
+enum Normalization {
+    /// `x / 255`, in `[0, 1]`.
+    ZeroToOne,
+    /// `x / 127.5 - 1`, in `[-1, 1]`, as in the Inception preprocessing.
+    MinusOneToOne,
+    /// `(x / 255 - mean) / std`, with a mean and a standard deviation for
+    /// each of the red, green, and blue channels.
+    MeanStd { mean: [f32; 3], std: [f32; 3] },
+}
+
+impl Normalization {
+    /// Normalize the value of a pixel for the given channel.
+    fn apply(&self, value: u8, channel: usize) -> f32 {
+        match self {
+            Normalization::ZeroToOne => value as f32 / 255.0,
+            Normalization::MinusOneToOne => value as f32 / 127.5 - 1.0,
+            Normalization::MeanStd { mean, std } => {
+                (value as f32 / 255.0 - mean[channel]) / std[channel]
+            }
+        }
+    }
+}
+
+/// Name of the input of the models that the images are fed to, as set by
+/// `set_input_name`, or empty to use the only input of the models.
+static INPUT_NAME: Mutex<String> = Mutex::new(String::new());
+
+/// Error of `load_model` when the input of the model cannot be selected,
+/// which is reported as `UNKNOWN_INPUT`.
+#[derive(Debug)]
+struct UnknownInput(String);
+
+impl std::fmt::Display for UnknownInput {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for UnknownInput {}
+
+/// Log-prior of each class, added to the logits of the model before
+/// picking the predicted class. Empty when there is no prior.
+static CLASS_PRIOR: Mutex<Vec<f32>> = Mutex::new(Vec::new());
+
+/// Whether the output of the model is already probabilities, such as the
+/// output of a softmax, instead of logits, as set by
+/// `set_output_probabilities`.
+static OUTPUT_PROBABILITIES: AtomicBool = AtomicBool::new(false);
+
+/// Scale and zero point of the quantized output of the model, set by
+/// `set_output_quantization`. `None` until they are set, for the default
+/// ones of `output_scores`.
+static OUTPUT_QUANTIZATION: Mutex<Option<(f32, i32)>> = Mutex::new(None);
+
+/// Labels of MobileNet V2, which are the labels of the label inference
+/// functions until others are set with `set_labels`, so that the module can
+/// classify images on its own.
+const MOBILENET_LABELS: &str = include_str!("../../../model/labels.txt");
+
+/// Labels of the label inference functions, with their label offset: the
+/// label of a class is on the line whose number is the index of the class
+/// minus the offset, as for the `--label-offset` of the host. `None` until
+/// they are first used or set, to parse the MobileNet labels, which have a
+/// line for the background class, only when needed.
+static LABELS: Mutex<Option<(Vec<String>, usize)>> = Mutex::new(None);
+
+/// Probability of each class in the last inference of this instance, in
+/// the order of the output of the model.
+static PROBABILITIES: Mutex<Vec<f32>> = Mutex::new(Vec::new());
+
+/// Whether the stages of the inferences are timed, as set by `set_timings`.
+static TIMINGS_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Duration in microseconds of each stage of the last inference of this
+/// instance, in the order of `Stage`, returned by `timings`.
+static TIMINGS: Mutex<[u64; 3]> = Mutex::new([0; 3]);
+
+/// Stage of an inference, which is timed when `set_timings` is enabled.
+#[derive(Clone, Copy)]
+enum Stage {
+    /// Decoding the image into RGB pixels.
+    Decode,
+    /// Cropping and resizing the image, and normalizing its pixels into the
+    /// input tensor of the model.
+    Preprocess,
+    /// Running the model on its input tensor.
+    Run,
+}
 
 /// Allocate memory into the module's linear memory
 /// and return the offset to the start of the block.
@@ -8,7 +218,18 @@ pub extern "C" fn alloc(len: usize) -> *mut u8 {
     let ptr = buf.as_mut_ptr();
 
     std::mem::forget(buf);
-    return ptr;
+    ptr
+}
+
+/// Free a block of memory allocated with `alloc`.
+///
+/// # Safety
+///
+/// The pointer must have been returned by `alloc`, the length must be the
+/// one that was passed to `alloc`, and the block must not be used anymore.
+#[no_mangle]
+pub unsafe extern "C" fn dealloc(ptr: *mut u8, len: usize) {
+    drop(Vec::from_raw_parts(ptr, 0, len));
 }
 
 /// This is the module's entry point for executing inferences.
@@ -18,64 +239,1389 @@ pub extern "C" fn alloc(len: usize) -> *mut u8 {
 /// the model and image data using the `alloc` function, then copy it
 /// into the module's linear memory at the pointers returned by `alloc`.
 ///
-/// It retrieves the contents of the model and image, then calls
-/// the `infer` function, which performs the prediction.
+/// It retrieves the contents of the model and image, compiles the model,
+/// then calls the `run_model` function, which performs the prediction.
+/// The memory blocks are only borrowed, and still belong to the caller,
+/// which can reuse them or free them with `dealloc`.
+///
+/// Returns the index of the predicted class, or `DECODE_FAILED`,
+/// `MODEL_LOAD_FAILED`, or `RUN_FAILED` if the corresponding step fails,
+/// or `IMAGE_TOO_LARGE` if the image is larger than the maximum dimension.
+///
+/// # Safety
+///
+/// The pointers must have been returned by `alloc`, and the lengths must
+/// match the number of bytes written at each pointer.
 #[no_mangle]
 pub unsafe extern "C" fn infer_from_ptrs(
-    model_ptr: *mut u8,
+    model_ptr: *const u8,
+    model_len: usize,
+    img_ptr: *const u8,
+    img_len: usize,
+) -> i32 {
+    infer_sized_from_ptrs(
+        model_ptr,
+        model_len,
+        img_ptr,
+        img_len,
+        DEFAULT_SIZE,
+        DEFAULT_SIZE,
+    )
+}
+
+/// Same as `infer_from_ptrs`, for a model whose input is an image of
+/// `width` x `height` pixels instead of 224 x 224.
+///
+/// Returns `INVALID_DIMENSIONS` if the width or the height is 0.
+///
+/// # Safety
+///
+/// The pointers must have been returned by `alloc`, and the lengths must
+/// match the number of bytes written at each pointer.
+#[no_mangle]
+pub unsafe extern "C" fn infer_sized_from_ptrs(
+    model_ptr: *const u8,
+    model_len: usize,
+    img_ptr: *const u8,
+    img_len: usize,
+    width: u32,
+    height: u32,
+) -> i32 {
+    let model_bytes = std::slice::from_raw_parts(model_ptr, model_len);
+    let img_bytes = std::slice::from_raw_parts(img_ptr, img_len);
+
+    if width == 0 || height == 0 {
+        return INVALID_DIMENSIONS;
+    }
+    let image = match decode_image(img_bytes) {
+        Ok(image) => image,
+        Err(code) => return code,
+    };
+    match compile_model(model_bytes, width, height) {
+        Ok(model) => run_model(&model, &image),
+        Err(_) => MODEL_LOAD_FAILED,
+    }
+}
+
+/// Compile the model copied at `model_ptr`, and return a handle that can
+/// be passed to `infer_with_handle` to run it.
+///
+/// Optimizing the model is by far the most expensive step of an inference,
+/// so compiled models are cached in the instance: loading the same bytes
+/// again returns the same handle without compiling the model, and all the
+/// inferences using the handle skip the compilation, which makes them an
+/// order of magnitude faster than with `infer_from_ptrs`.
+///
+/// The model bytes are not needed anymore once this function returns, and
+/// can be freed with `dealloc`.
+///
+/// Returns `MODEL_LOAD_FAILED` if the model cannot be compiled.
+///
+/// # Safety
+///
+/// The pointer must have been returned by `alloc`, and the length must
+/// match the number of bytes written at the pointer.
+#[no_mangle]
+pub unsafe extern "C" fn load_model_from_ptrs(model_ptr: *const u8, model_len: usize) -> i32 {
+    load_sized_model_from_ptrs(model_ptr, model_len, DEFAULT_SIZE, DEFAULT_SIZE)
+}
+
+/// Same as `load_model_from_ptrs`, for a model whose input is an image of
+/// `width` x `height` pixels instead of 224 x 224. The images passed to the
+/// inference functions with the handle are resized to these dimensions.
+///
+/// Returns `INVALID_DIMENSIONS` if the width or the height is 0.
+///
+/// # Safety
+///
+/// The pointer must have been returned by `alloc`, and the length must
+/// match the number of bytes written at the pointer.
+#[no_mangle]
+pub unsafe extern "C" fn load_sized_model_from_ptrs(
+    model_ptr: *const u8,
+    model_len: usize,
+    width: u32,
+    height: u32,
+) -> i32 {
+    let model_bytes = std::slice::from_raw_parts(model_ptr, model_len);
+
+    if width == 0 || height == 0 {
+        return INVALID_DIMENSIONS;
+    }
+    let mut hasher = DefaultHasher::new();
+    model_bytes.hash(&mut hasher);
+    (width, height).hash(&mut hasher);
+    INPUT_NAME.lock().unwrap().hash(&mut hasher);
+    let hash = hasher.finish();
+
+    MODELS.with(|models| {
+        let mut models = models.borrow_mut();
+        if let Some(handle) = models.iter().position(|(h, _)| *h == hash) {
+            return handle as i32;
+        }
+        match compile_model(model_bytes, width, height) {
+            Ok(model) => {
+                models.push((hash, Rc::new(model)));
+                models.len() as i32 - 1
+            }
+            Err(err) if err.is::<UnknownInput>() => UNKNOWN_INPUT,
+            Err(_) => MODEL_LOAD_FAILED,
+        }
+    })
+}
+
+/// Same as `infer_from_ptrs`, but running a model compiled by
+/// `load_model_from_ptrs` instead of compiling the model again.
+///
+/// Returns `UNKNOWN_MODEL` if the handle was not returned by
+/// `load_model_from_ptrs`, or `DECODE_FAILED` or `RUN_FAILED` if the
+/// corresponding step fails.
+///
+/// # Safety
+///
+/// The pointer must have been returned by `alloc`, and the length must
+/// match the number of bytes written at the pointer.
+#[no_mangle]
+pub unsafe extern "C" fn infer_with_handle(handle: i32, img_ptr: *const u8, img_len: usize) -> i32 {
+    let img_bytes = std::slice::from_raw_parts(img_ptr, img_len);
+    reset_timings();
+
+    let model = match model_from_handle(handle) {
+        Some(model) => model,
+        None => return UNKNOWN_MODEL,
+    };
+    match decode_image(img_bytes) {
+        Ok(image) => run_model(&model, &image),
+        Err(code) => code,
+    }
+}
+
+/// Same as `infer_from_ptrs`, except that the label of the predicted class
+/// is written at `out_ptr`, as UTF-8, instead of returning its index, for
+/// callers that do not have the labels of the model. The labels are the ones
+/// of MobileNet V2, unless others were set with `set_labels`.
+///
+/// Returns the length of the label, of which only the first `out_len` bytes
+/// are written, `UNKNOWN_LABEL` if the predicted class has no label, or the
+/// same errors as `infer_from_ptrs`.
+///
+/// # Safety
+///
+/// The pointers must have been returned by `alloc`, and the lengths must
+/// match the number of bytes written at each pointer. `out_ptr` must point
+/// to at least `out_len` bytes allocated with `alloc`.
+#[no_mangle]
+pub unsafe extern "C" fn infer_label_from_ptrs(
+    model_ptr: *const u8,
+    model_len: usize,
+    img_ptr: *const u8,
+    img_len: usize,
+    out_ptr: *mut u8,
+    out_len: usize,
+) -> i32 {
+    let class = infer_from_ptrs(model_ptr, model_len, img_ptr, img_len);
+    write_label(class, out_ptr, out_len)
+}
+
+/// Same as `infer_label_from_ptrs`, but running a model compiled by
+/// `load_model_from_ptrs` instead of compiling the model again.
+///
+/// # Safety
+///
+/// The pointer must have been returned by `alloc`, and the length must
+/// match the number of bytes written at the pointer. `out_ptr` must point
+/// to at least `out_len` bytes allocated with `alloc`.
+#[no_mangle]
+pub unsafe extern "C" fn infer_label_with_handle(
+    handle: i32,
+    img_ptr: *const u8,
+    img_len: usize,
+    out_ptr: *mut u8,
+    out_len: usize,
+) -> i32 {
+    let class = infer_with_handle(handle, img_ptr, img_len);
+    write_label(class, out_ptr, out_len)
+}
+
+/// Set the labels of the label inference functions of this instance, for
+/// models other than MobileNet V2, from the contents of a labels file copied
+/// at `labels_ptr`: UTF-8 text with the label of each class on the line
+/// whose number is the index of the class minus `label_offset`, such as 1
+/// for the MobileNet labels files without a line for the background class.
+/// The classes before the first line have no label.
+///
+/// Returns 0, or -1 if the labels are not valid UTF-8.
+///
+/// # Safety
+///
+/// The pointer must have been returned by `alloc`, and the length must
+/// match the number of bytes written at the pointer.
+#[no_mangle]
+pub unsafe extern "C" fn set_labels(
+    labels_ptr: *const u8,
+    labels_len: usize,
+    label_offset: usize,
+) -> i32 {
+    let labels = std::slice::from_raw_parts(labels_ptr, labels_len);
+    match std::str::from_utf8(labels) {
+        Ok(labels) => {
+            *LABELS.lock().unwrap() = Some((parse_labels(labels), label_offset));
+            0
+        }
+        Err(_) => -1,
+    }
+}
+
+/// Split a labels file into its labels, without the byte order mark or the
+/// trailing carriage returns and spaces some tools add, as the host does.
+fn parse_labels(labels: &str) -> Vec<String> {
+    labels
+        .trim_start_matches('\u{feff}')
+        .lines()
+        .map(|label| label.trim_end().to_string())
+        .collect()
+}
+
+/// Write the label of `class` at `out_ptr`, and return its length, or
+/// return `class` itself when it is an error.
+unsafe fn write_label(class: i32, out_ptr: *mut u8, out_len: usize) -> i32 {
+    if class < 0 {
+        return class;
+    }
+    let mut labels = LABELS.lock().unwrap();
+    let (labels, offset) = labels.get_or_insert_with(|| (parse_labels(MOBILENET_LABELS), 0));
+    let line = (class as usize).checked_sub(1 + *offset);
+    let label = match line.and_then(|i| labels.get(i)) {
+        Some(label) => label.as_bytes(),
+        None => return UNKNOWN_LABEL,
+    };
+
+    let len = label.len().min(out_len);
+    std::slice::from_raw_parts_mut(out_ptr, len).copy_from_slice(&label[..len]);
+    label.len() as i32
+}
+
+/// Same as `infer_random_crop_from_ptrs`, but running a model compiled by
+/// `load_model_from_ptrs` instead of compiling the model again.
+///
+/// Returns `UNKNOWN_MODEL` if the handle was not returned by
+/// `load_model_from_ptrs`, `CROP_OUT_OF_BOUNDS` if the crop does not fit
+/// in the image, or `DECODE_FAILED` or `RUN_FAILED` if the corresponding
+/// step fails.
+///
+/// # Safety
+///
+/// The pointer must have been returned by `alloc`, and the length must
+/// match the number of bytes written at the pointer.
+#[no_mangle]
+pub unsafe extern "C" fn infer_random_crop_with_handle(
+    handle: i32,
+    img_ptr: *const u8,
+    img_len: usize,
+    crop_size: u32,
+    seed: u64,
+) -> i32 {
+    let img_bytes = std::slice::from_raw_parts(img_ptr, img_len);
+    reset_timings();
+
+    let model = match model_from_handle(handle) {
+        Some(model) => model,
+        None => return UNKNOWN_MODEL,
+    };
+    let image = match decode_image(img_bytes) {
+        Ok(image) => image,
+        Err(code) => return code,
+    };
+    match timed(Stage::Preprocess, || random_crop(&image, crop_size, seed)) {
+        Some(cropped) => run_model(&model, &cropped),
+        None => CROP_OUT_OF_BOUNDS,
+    }
+}
+
+/// Run a model compiled by `load_model_from_ptrs` on several images at once,
+/// with an input of shape `(N, height, width, 3)` instead of `(1, ...)`.
+///
+/// The images are copied at `images_ptr` one after the other, each prefixed
+/// by its length as a little-endian `u32`. For each image, in the same order,
+/// a little-endian `i32` class index, followed by the `f32` probability of
+/// the class, is written at `out_ptr`. Images that cannot be decoded are left
+/// out of the batch, and get `DECODE_FAILED` or `IMAGE_TOO_LARGE` instead of a
+/// class index, with a probability of 0.
+///
+/// Returns the number of images, or `UNKNOWN_MODEL` if the handle was not
+/// returned by `load_model_from_ptrs`, `INVALID_BATCH` if the images are not
+/// packed as expected, or `MODEL_LOAD_FAILED` or `RUN_FAILED` if compiling
+/// or running the batched model fails. Unlike the other inference functions,
+/// this does not change the probabilities returned by `top_k`.
+///
+/// # Safety
+///
+/// The images pointer must have been returned by `alloc`, and the length
+/// must match the number of bytes written at the pointer. `out_ptr` must
+/// point to at least 8 bytes per image allocated with `alloc`.
+#[no_mangle]
+pub unsafe extern "C" fn infer_batch_with_handle(
+    handle: i32,
+    images_ptr: *const u8,
+    images_len: usize,
+    out_ptr: *mut u8,
+) -> i32 {
+    let mut images_bytes = std::slice::from_raw_parts(images_ptr, images_len);
+
+    let model = match model_from_handle(handle) {
+        Some(model) => model,
+        None => return UNKNOWN_MODEL,
+    };
+    let mut images = Vec::new();
+    while !images_bytes.is_empty() {
+        if images_bytes.len() < 4 {
+            return INVALID_BATCH;
+        }
+        let (len, rest) = images_bytes.split_at(4);
+        let len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        if rest.len() < len {
+            return INVALID_BATCH;
+        }
+        let (img_bytes, rest) = rest.split_at(len);
+        images.push(decode_image(img_bytes));
+        images_bytes = rest;
+    }
+
+    let decoded: Vec<&RgbImage> = images.iter().flatten().collect();
+    let mut predictions = match run_batch(&model, &decoded) {
+        Ok(predictions) => predictions.into_iter(),
+        Err(code) => return code,
+    };
+
+    let out = std::slice::from_raw_parts_mut(out_ptr, images.len() * 8);
+    for (pair, image) in out.chunks_mut(8).zip(&images) {
+        let (class, probability) = match image {
+            Ok(_) => predictions.next().unwrap_or((RUN_FAILED, 0.0)),
+            Err(code) => (*code, 0.0),
+        };
+        pair[..4].copy_from_slice(&class.to_le_bytes());
+        pair[4..].copy_from_slice(&probability.to_le_bytes());
+    }
+    images.len() as i32
+}
+
+/// Same as `infer_from_ptrs`, for an input tensor that was already built
+/// from an image, instead of the image itself, so that callers that already
+/// resized and normalized the image skip its decoding and resizing.
+///
+/// The tensor has the `(1, 224, 224, 3)` shape, as little-endian `f32`
+/// values in row-major order, which are passed to the model as they are.
+///
+/// Returns `INVALID_TENSOR` if the tensor is not `1 * 224 * 224 * 3` finite
+/// values, or `MODEL_LOAD_FAILED` or `RUN_FAILED` if the corresponding step fails.
+///
+/// # Safety
+///
+/// The pointers must have been returned by `alloc`, and the lengths must
+/// match the number of bytes written at each pointer.
+#[no_mangle]
+pub unsafe extern "C" fn infer_tensor_from_ptrs(
+    model_ptr: *const u8,
+    model_len: usize,
+    tensor_ptr: *const u8,
+    tensor_len: usize,
+) -> i32 {
+    let model_bytes = std::slice::from_raw_parts(model_ptr, model_len);
+    let tensor_bytes = std::slice::from_raw_parts(tensor_ptr, tensor_len);
+
+    match compile_model(model_bytes, DEFAULT_SIZE, DEFAULT_SIZE) {
+        Ok(model) => run_tensor(&model, tensor_bytes),
+        Err(_) => MODEL_LOAD_FAILED,
+    }
+}
+
+/// Same as `infer_tensor_from_ptrs`, but running a model compiled by
+/// `load_model_from_ptrs` instead of compiling the model again. The tensor
+/// has the `(1, height, width, 3)` shape of the input of the model.
+///
+/// Returns `UNKNOWN_MODEL` if the handle was not returned by
+/// `load_model_from_ptrs`, `INVALID_TENSOR` if the tensor does not have the
+/// shape of the input or has values that are not finite, or `RUN_FAILED` if running the model fails.
+///
+/// # Safety
+///
+/// The pointer must have been returned by `alloc`, and the length must
+/// match the number of bytes written at the pointer.
+#[no_mangle]
+pub unsafe extern "C" fn infer_tensor_with_handle(
+    handle: i32,
+    tensor_ptr: *const u8,
+    tensor_len: usize,
+) -> i32 {
+    let tensor_bytes = std::slice::from_raw_parts(tensor_ptr, tensor_len);
+    reset_timings();
+
+    match model_from_handle(handle) {
+        Some(model) => run_tensor(&model, tensor_bytes),
+        None => UNKNOWN_MODEL,
+    }
+}
+
+/// Same as `infer_from_ptrs`, but write the whole output of the model
+/// instead of returning the predicted class, so that the caller can decode
+/// it, such as for detection or multi-label models.
+///
+/// The output is the first output tensor of the model, before the class
+/// prior or the background masking are applied, and is copied as
+/// little-endian `f32` values, in row-major order, to a block allocated with
+/// `alloc`. Its shape, such as `(1, 1001)` for MobileNet V2, does not depend
+/// on the image. A header of `RAW_OUTPUT_HEADER` (40) bytes is written at
+/// `out_ptr`: the pointer to the values, the number of dimensions, and each
+/// of the (up to 8) dimensions, as little-endian `u32`s. The caller owns the
+/// values, and must free them with `dealloc`, with a length of 4 bytes per
+/// value.
+///
+/// Returns the number of values, or `DECODE_FAILED`, `MODEL_LOAD_FAILED`,
+/// or `RUN_FAILED` if the corresponding step fails. Nothing is written at
+/// `out_ptr` on failure.
+///
+/// # Safety
+///
+/// The pointers must have been returned by `alloc`, and the lengths must
+/// match the number of bytes written at each pointer. `out_ptr` must point
+/// to at least `RAW_OUTPUT_HEADER` bytes allocated with `alloc`.
+#[no_mangle]
+pub unsafe extern "C" fn infer_raw_from_ptrs(
+    model_ptr: *const u8,
+    model_len: usize,
+    img_ptr: *const u8,
+    img_len: usize,
+    out_ptr: *mut u8,
+) -> i32 {
+    let model_bytes = std::slice::from_raw_parts(model_ptr, model_len);
+    let img_bytes = std::slice::from_raw_parts(img_ptr, img_len);
+
+    let image = match decode_image(img_bytes) {
+        Ok(image) => image,
+        Err(code) => return code,
+    };
+    match compile_model(model_bytes, DEFAULT_SIZE, DEFAULT_SIZE) {
+        Ok(model) => write_raw_output(model_output(&model, &image), out_ptr),
+        Err(_) => MODEL_LOAD_FAILED,
+    }
+}
+
+/// Same as `infer_raw_from_ptrs`, but running a model compiled by
+/// `load_model_from_ptrs` instead of compiling the model again.
+///
+/// Returns `UNKNOWN_MODEL` if the handle was not returned by
+/// `load_model_from_ptrs`.
+///
+/// # Safety
+///
+/// The pointer must have been returned by `alloc`, and the length must
+/// match the number of bytes written at the pointer. `out_ptr` must point
+/// to at least `RAW_OUTPUT_HEADER` bytes allocated with `alloc`.
+#[no_mangle]
+pub unsafe extern "C" fn infer_raw_with_handle(
+    handle: i32,
+    img_ptr: *const u8,
+    img_len: usize,
+    out_ptr: *mut u8,
+) -> i32 {
+    let img_bytes = std::slice::from_raw_parts(img_ptr, img_len);
+
+    let model = match model_from_handle(handle) {
+        Some(model) => model,
+        None => return UNKNOWN_MODEL,
+    };
+    match decode_image(img_bytes) {
+        Ok(image) => write_raw_output(model_output(&model, &image), out_ptr),
+        Err(code) => code,
+    }
+}
+
+/// Copy the output of the model to a new block of memory, and write the
+/// header described in `infer_raw_from_ptrs` at `out_ptr`.
+unsafe fn write_raw_output(output: TractResult<Arc<Tensor>>, out_ptr: *mut u8) -> i32 {
+    let output = match output {
+        Ok(output) if output.rank() <= MAX_OUTPUT_RANK => output,
+        _ => return RUN_FAILED,
+    };
+    // The output of quantized models is converted to `f32` values, without
+    // being dequantized, so that all models have the same raw layout.
+    let output = match output.cast_to::<f32>() {
+        Ok(output) => output,
+        Err(_) => return RUN_FAILED,
+    };
+    let values = match output.to_array_view::<f32>() {
+        Ok(values) => values,
+        Err(_) => return RUN_FAILED,
+    };
+
+    let bytes: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+    let values_ptr = alloc(bytes.len());
+    values_ptr.copy_from(bytes.as_ptr(), bytes.len());
+
+    let header = std::slice::from_raw_parts_mut(out_ptr, RAW_OUTPUT_HEADER);
+    let fields = std::iter::once(values_ptr as u32)
+        .chain(std::iter::once(output.rank() as u32))
+        .chain(output.shape().iter().map(|dim| *dim as u32))
+        .chain(std::iter::repeat(0));
+    for (field, value) in header.chunks_mut(4).zip(fields) {
+        field.copy_from_slice(&value.to_le_bytes());
+    }
+    values.len() as i32
+}
+
+/// Get a model compiled by `load_model_from_ptrs` from its handle.
+fn model_from_handle(handle: i32) -> Option<Rc<Model>> {
+    MODELS.with(|models| {
+        models
+            .borrow()
+            .get(handle as usize)
+            .filter(|_| handle >= 0)
+            .map(|(_, model)| model.clone())
+    })
+}
+
+/// Return the dimensions of the image copied at `img_ptr`, packed as
+/// `(width << 32) | height`, or -1 if the format of the image is not
+/// recognized. Only the header of the image is decoded.
+///
+/// The image is only borrowed, so the same pointer can then be passed
+/// to one of the inference functions.
+///
+/// # Safety
+///
+/// The pointer must have been returned by `alloc`, and the length must
+/// match the number of bytes written at the pointer.
+#[no_mangle]
+pub unsafe extern "C" fn image_dimensions(img_ptr: *const u8, img_len: usize) -> i64 {
+    let img_bytes = std::slice::from_raw_parts(img_ptr, img_len);
+
+    let dimensions = image::io::Reader::new(std::io::Cursor::new(img_bytes))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_dimensions().ok());
+    match dimensions {
+        Some((width, height)) => ((width as i64) << 32) | height as i64,
+        None => -1,
+    }
+}
+
+/// Write statistics about the model graph at `out_ptr`, as four little-endian
+/// `u32`s: the number of nodes and of distinct operators in the graph, then
+/// the same numbers after it has been optimized by `into_optimized()`.
+///
+/// This is only used for diagnostics, to understand the impact of the
+/// optimization and to catch unexpectedly large models.
+///
+/// Returns 0, or `MODEL_LOAD_FAILED` if the model cannot be loaded or
+/// optimized, in which case nothing is written.
+///
+/// # Safety
+///
+/// The model pointer must have been returned by `alloc`, and the length must
+/// match the number of bytes written at the pointer. `out_ptr` must point to
+/// at least 16 bytes allocated with `alloc`.
+#[no_mangle]
+pub unsafe extern "C" fn model_stats(
+    model_ptr: *const u8,
+    model_len: usize,
+    out_ptr: *mut u8,
+) -> i32 {
+    let model_bytes = std::slice::from_raw_parts(model_ptr, model_len);
+
+    let model = match load_model(model_bytes, DEFAULT_SIZE, DEFAULT_SIZE) {
+        Ok(model) => model,
+        Err(_) => return MODEL_LOAD_FAILED,
+    };
+    let nodes = model.nodes().len();
+    let ops = model
+        .nodes()
+        .iter()
+        .map(|n| n.op.name())
+        .collect::<HashSet<_>>()
+        .len();
+
+    let optimized = match model.into_optimized() {
+        Ok(optimized) => optimized,
+        Err(_) => return MODEL_LOAD_FAILED,
+    };
+    let optimized_nodes = optimized.nodes().len();
+    let optimized_ops = optimized
+        .nodes()
+        .iter()
+        .map(|n| n.op.name())
+        .collect::<HashSet<_>>()
+        .len();
+
+    let out = std::slice::from_raw_parts_mut(out_ptr, 16);
+    for (chunk, val) in out
+        .chunks_mut(4)
+        .zip(&[nodes, ops, optimized_nodes, optimized_ops])
+    {
+        chunk.copy_from_slice(&(*val as u32).to_le_bytes());
+    }
+    0
+}
+
+/// Return the number of classes of the model, whose input is an image of
+/// `width` x `height` pixels, which is the size of the last dimension of
+/// its output, or -1 if it cannot be determined.
+///
+/// # Safety
+///
+/// The pointer must have been returned by `alloc`, and the length must
+/// match the number of bytes written at the pointer.
+#[no_mangle]
+pub unsafe extern "C" fn model_classes(
+    model_ptr: *const u8,
+    model_len: usize,
+    width: u32,
+    height: u32,
+) -> i32 {
+    let model_bytes = std::slice::from_raw_parts(model_ptr, model_len);
+
+    let shape = load_model(model_bytes, width, height)
+        .and_then(|model| model.into_typed())
+        .and_then(|model| Ok(model.output_fact(0)?.shape.as_finite()));
+    match shape {
+        Ok(Some(shape)) => shape.last().map_or(-1, |classes| *classes as i32),
+        _ => -1,
+    }
+}
+
+/// Write a description of the inputs of the model at `out_ptr`, as UTF-8
+/// text with a line for each input: its name, the type of its values, and
+/// its dimensions, separated by tabs, such as `input\tf32\t?,224,224,3`.
+/// The type and the dimensions that the graph does not specify are `?`,
+/// and the dimensions are `*` when the graph does not specify their number.
+///
+/// This lets the host find the input to select with `set_input_name`, and
+/// the dimensions of the images to pass to `load_sized_model_from_ptrs`.
+///
+/// Returns the length of the whole description, of which only the first
+/// `out_len` bytes are written, so a longer description can be read by
+/// calling this again with a larger buffer, or `MODEL_LOAD_FAILED` if the
+/// model cannot be loaded.
+///
+/// # Safety
+///
+/// The model pointer must have been returned by `alloc`, and the length must
+/// match the number of bytes written at the pointer. `out_ptr` must point to
+/// at least `out_len` bytes allocated with `alloc`.
+#[no_mangle]
+pub unsafe extern "C" fn model_inputs(
+    model_ptr: *const u8,
+    model_len: usize,
+    out_ptr: *mut u8,
+    out_len: usize,
+) -> i32 {
+    let model_bytes = std::slice::from_raw_parts(model_ptr, model_len);
+
+    let describe = || -> TractResult<String> {
+        let model = read_graph(model_bytes)?;
+        let mut description = String::new();
+        for (i, name) in input_names(&model)?.iter().enumerate() {
+            let fact = model.input_fact(i)?;
+            let datum_type = fact
+                .datum_type
+                .concretize()
+                .map_or("?".to_string(), |dt| format!("{:?}", dt).to_lowercase());
+            let dims = if fact.shape.is_open() {
+                "*".to_string()
+            } else {
+                fact.shape
+                    .dims()
+                    .map(|dim| match dim.concretize().map(|d| d.to_i64()) {
+                        Some(Ok(d)) => d.to_string(),
+                        _ => "?".to_string(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(",")
+            };
+            description.push_str(&format!("{}\t{}\t{}\n", name, datum_type, dims));
+        }
+        Ok(description)
+    };
+    let description = match describe() {
+        Ok(description) => description,
+        Err(_) => return MODEL_LOAD_FAILED,
+    };
+
+    let len = description.len().min(out_len);
+    std::slice::from_raw_parts_mut(out_ptr, len).copy_from_slice(&description.as_bytes()[..len]);
+    description.len() as i32
+}
+
+/// Select the input of the models to feed the images to, by the name of its
+/// node, for the models loaded or inspected after this call in this
+/// instance, which are otherwise expected to have a single input. The name
+/// is copied at `name_ptr`, and empty to go back to the single input.
+///
+/// Loading a model without the selected input returns `UNKNOWN_INPUT`.
+///
+/// # Safety
+///
+/// The pointer must have been returned by `alloc`, and the length must
+/// match the number of bytes written at the pointer.
+#[no_mangle]
+pub unsafe extern "C" fn set_input_name(name_ptr: *const u8, name_len: usize) -> i32 {
+    let name = std::slice::from_raw_parts(name_ptr, name_len);
+    match std::str::from_utf8(name) {
+        Ok(name) => {
+            *INPUT_NAME.lock().unwrap() = name.to_string();
+            0
+        }
+        Err(_) => -1,
+    }
+}
+
+/// Write the `k` most likely classes of the last inference of this instance
+/// at `out_ptr`, most likely first, as pairs of a little-endian `u32` class
+/// index (with the same numbering as the inference functions) and `f32`
+/// probability. `k` is clamped to the number of classes of the model.
+///
+/// The probabilities take the class prior and the background mask into
+/// account, and sum to 1 over all classes.
+///
+/// Returns the number of pairs that were written, which is 0 if there was
+/// no inference yet.
+///
+/// # Safety
+///
+/// `out_ptr` must point to at least `k * 8` bytes allocated with `alloc`.
+#[no_mangle]
+pub unsafe extern "C" fn top_k(k: u32, out_ptr: *mut u8) -> i32 {
+    let probabilities = PROBABILITIES.lock().unwrap();
+    let mut classes: Vec<(usize, f32)> = probabilities.iter().cloned().enumerate().collect();
+    // Probabilities that are not a number, such as from a model whose
+    // output overflows, are ordered with the others instead of panicking.
+    classes.sort_by(|a, b| b.1.total_cmp(&a.1));
+    classes.truncate(k as usize);
+
+    let out = std::slice::from_raw_parts_mut(out_ptr, classes.len() * 8);
+    for (pair, (index, probability)) in out.chunks_mut(8).zip(&classes) {
+        pair[..4].copy_from_slice(&(*index as u32 + 1).to_le_bytes());
+        pair[4..].copy_from_slice(&probability.to_le_bytes());
+    }
+    classes.len() as i32
+}
+
+/// Write the duration of each stage of the last inference of this instance
+/// by `infer_with_handle`, `infer_random_crop_with_handle`, or
+/// `infer_tensor_with_handle` at `out_ptr`, as three little-endian `u64`
+/// numbers of microseconds: decoding the image, preprocessing it into the
+/// input tensor of the model, and running the model. Stages that did not
+/// run, such as the decoding of a tensor, take 0 microseconds.
+///
+/// Returns the number of durations that were written, which is 0 when the
+/// timings are not enabled by `set_timings`.
+///
+/// # Safety
+///
+/// `out_ptr` must point to at least 24 bytes allocated with `alloc`.
+#[no_mangle]
+pub unsafe extern "C" fn timings(out_ptr: *mut u8) -> i32 {
+    if !TIMINGS_ENABLED.load(Ordering::Relaxed) {
+        return 0;
+    }
+    let timings = TIMINGS.lock().unwrap();
+    let out = std::slice::from_raw_parts_mut(out_ptr, timings.len() * 8);
+    for (bytes, micros) in out.chunks_mut(8).zip(timings.iter()) {
+        bytes.copy_from_slice(&micros.to_le_bytes());
+    }
+    timings.len() as i32
+}
+
+/// Set the maximum width and height of the images of the following
+/// inferences of this instance, which is 10000 pixels by default. Larger
+/// images get `IMAGE_TOO_LARGE` before being decoded, since a small file
+/// can decode into an image that does not fit in the memory of the module.
+#[no_mangle]
+pub extern "C" fn set_max_dimension(max: u32) {
+    MAX_DIMENSION.store(max, Ordering::Relaxed);
+}
+
+/// Time (when `enabled` is not 0) or stop timing the stages of the
+/// following inferences of this instance, for `timings`. They are not
+/// timed by default, so that inferences do not pay for reading the clock.
+#[no_mangle]
+pub extern "C" fn set_timings(enabled: u32) {
+    TIMINGS_ENABLED.store(enabled != 0, Ordering::Relaxed);
+    reset_timings();
+}
+
+/// Take the output of the models of this instance as probabilities (when
+/// `enabled` is not 0), such as the output of a softmax, or as logits,
+/// which is the default. The log of the probabilities is used as their
+/// logits, so that the class prior and the softmax of the probabilities
+/// returned by `top_k` apply to both.
+#[no_mangle]
+pub extern "C" fn set_output_probabilities(enabled: u32) {
+    OUTPUT_PROBABILITIES.store(enabled != 0, Ordering::Relaxed);
+}
+
+/// Exclude (when `skip` is not 0) or include the first output of the model,
+/// which is the background class for MobileNet, when picking the predicted
+/// class in the following inferences of this instance.
+///
+/// This only masks the score of the class, and does not change the
+/// indices that are returned.
+#[no_mangle]
+pub extern "C" fn set_skip_background(skip: u32) {
+    SKIP_BACKGROUND.store(skip != 0, Ordering::Relaxed);
+}
+
+/// Set the filter used to resize the images of the following inferences of
+/// this instance: 0 for nearest neighbor, 1 for a linear (triangle) filter,
+/// which is the default, 2 for Catmull-Rom, 3 for a Gaussian filter, or 4
+/// for Lanczos with a window of 3.
+///
+/// The filter is a tradeoff between speed and quality, and changing it can
+/// slightly change the scores, and sometimes the predicted class, of the
+/// same image. Returns -1, without changing the filter, if it is unknown.
+#[no_mangle]
+pub extern "C" fn set_resize_filter(filter: u32) -> i32 {
+    if filter > 4 {
+        return -1;
+    }
+    RESIZE_FILTER.store(filter, Ordering::Relaxed);
+    0
+}
+
+/// Set how the images of the following inferences of this instance are
+/// resized to the input dimensions of the model: 0 to resize the whole
+/// image, which distorts images that do not have the aspect ratio of the
+/// model, and is the default, or 1 to resize it without distorting it, and
+/// keep its center. Returns -1, without changing the mode, if it is unknown.
+#[no_mangle]
+pub extern "C" fn set_preprocess(mode: u32) -> i32 {
+    if mode > 1 {
+        return -1;
+    }
+    CENTER_CROP.store(mode == 1, Ordering::Relaxed);
+    0
+}
+
+/// Set how every pixel of the following inferences of this instance is
+/// normalized: `mode` is 0 to scale the pixels to `[0, 1]`, 1 to scale
+/// them to `[-1, 1]`, or 2 to normalize them as `(x / 255 - mean) / std`,
+/// with a mean and a standard deviation for each of the red, green, and blue
+/// channels, which are ignored by the other modes.
+///
+/// By default, pixels are scaled to `[0, 1]`. Returns -1, without changing
+/// the normalization, if the mode is unknown.
+#[no_mangle]
+pub extern "C" fn set_normalization(
+    mode: u32,
+    mean_r: f32,
+    mean_g: f32,
+    mean_b: f32,
+    std_r: f32,
+    std_g: f32,
+    std_b: f32,
+) -> i32 {
+    let normalization = match mode {
+        0 => Normalization::ZeroToOne,
+        1 => Normalization::MinusOneToOne,
+        2 => Normalization::MeanStd {
+            mean: [mean_r, mean_g, mean_b],
+            std: [std_r, std_g, std_b],
+        },
+        _ => return -1,
+    };
+    *NORMALIZATION.lock().unwrap() = normalization;
+    0
+}
+
+/// Add a log-prior to the logit of each class in the following inferences
+/// of this instance, to correct for classes that are more or less frequent
+/// than in the training data. The prior is copied at `prior_ptr`, as one
+/// little-endian `f32` per class of the model.
+///
+/// For models whose output is probabilities (see `set_output_probabilities`),
+/// the prior is added to the log of the probabilities.
+///
+/// # Safety
+///
+/// The pointer must have been returned by `alloc`, and the length must
+/// match the number of bytes written at the pointer.
+#[no_mangle]
+pub unsafe extern "C" fn set_class_prior(prior_ptr: *const u8, prior_len: usize) {
+    let prior_bytes = std::slice::from_raw_parts(prior_ptr, prior_len);
+
+    *CLASS_PRIOR.lock().unwrap() = prior_bytes
+        .chunks_exact(4)
+        .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+        .collect();
+}
+
+/// Set how the quantized output of the models of this instance, in `u8` or
+/// `i8` values, is dequantized into probabilities, as
+/// `(q - zero_point) * scale`, such as with a scale of 1/256 and a zero
+/// point of -128 for the `i8` softmax of TensorFlow Lite models. The output
+/// of models that are not quantized is not changed.
+///
+/// Returns -1, without changing the quantization, if the scale is not a
+/// positive number.
+#[no_mangle]
+pub extern "C" fn set_output_quantization(scale: f32, zero_point: i32) -> i32 {
+    if !(scale.is_finite() && scale > 0.0) {
+        return -1;
+    }
+    *OUTPUT_QUANTIZATION.lock().unwrap() = Some((scale, zero_point));
+    0
+}
+
+/// Same as `infer_from_ptrs`, except that a square of `crop_size` pixels is
+/// first cropped from the image, at a random position derived from `seed`.
+/// The same seed always results in the same crop for a given image, which
+/// makes augmentation experiments reproducible.
+///
+/// Returns `CROP_OUT_OF_BOUNDS` if the crop does not fit in the image, or
+/// the same errors as `infer_from_ptrs`.
+///
+/// # Safety
+///
+/// The pointers must have been returned by `alloc`, and the lengths must
+/// match the number of bytes written at each pointer.
+#[no_mangle]
+pub unsafe extern "C" fn infer_random_crop_from_ptrs(
+    model_ptr: *const u8,
     model_len: usize,
-    img_ptr: *mut u8,
+    img_ptr: *const u8,
     img_len: usize,
+    crop_size: u32,
+    seed: u64,
 ) -> i32 {
-    let model_bytes = Vec::from_raw_parts(model_ptr, model_len, model_len);
-    let img_bytes = Vec::from_raw_parts(img_ptr, img_len, img_len);
+    let model_bytes = std::slice::from_raw_parts(model_ptr, model_len);
+    let img_bytes = std::slice::from_raw_parts(img_ptr, img_len);
+
+    let image = match decode_image(img_bytes) {
+        Ok(image) => image,
+        Err(code) => return code,
+    };
+    let cropped = match random_crop(&image, crop_size, seed) {
+        Some(cropped) => cropped,
+        None => return CROP_OUT_OF_BOUNDS,
+    };
+    match compile_model(model_bytes, DEFAULT_SIZE, DEFAULT_SIZE) {
+        Ok(model) => run_model(&model, &cropped),
+        Err(_) => MODEL_LOAD_FAILED,
+    }
+}
+
+/// Crop a square of `size` pixels from the image, at a position chosen by
+/// a pseudo-random number generator seeded with `seed`.
+/// Returns `None` if the crop does not fit in the image.
+fn random_crop(image: &RgbImage, size: u32, seed: u64) -> Option<RgbImage> {
+    let (width, height) = image.dimensions();
+    if size == 0 || size > width || size > height {
+        return None;
+    }
+
+    let mut rng = SplitMix64(seed);
+    let x = (rng.next() % (width - size + 1) as u64) as u32;
+    let y = (rng.next() % (height - size + 1) as u64) as u32;
+
+    Some(image::imageops::crop_imm(image, x, y, size, size).to_image())
+}
+
+/// Minimal SplitMix64 pseudo-random number generator, which is more than
+/// enough for picking crop positions and avoids depending on `rand`.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+/// Decode an image in any of the supported formats into RGB pixels, or
+/// return `DECODE_FAILED`, or `IMAGE_TOO_LARGE` if the dimensions in its
+/// header are larger than `MAX_DIMENSION`, without decoding its pixels.
+fn decode_image(img_bytes: &[u8]) -> Result<RgbImage, i32> {
+    timed(Stage::Decode, || {
+        let max = MAX_DIMENSION.load(Ordering::Relaxed);
+        let dimensions = image::io::Reader::new(std::io::Cursor::new(img_bytes))
+            .with_guessed_format()
+            .ok()
+            .and_then(|reader| reader.into_dimensions().ok());
+        match dimensions {
+            Some((width, height)) if width > max || height > max => return Err(IMAGE_TOO_LARGE),
+            Some(_) => {}
+            None => return Err(DECODE_FAILED),
+        }
+        image::load_from_memory(img_bytes)
+            .map(|image| image.to_rgb8())
+            .map_err(|_| DECODE_FAILED)
+    })
+}
+
+/// Run `f`, adding its duration to the timings of `stage` when the timings
+/// are enabled.
+fn timed<T>(stage: Stage, f: impl FnOnce() -> T) -> T {
+    if !TIMINGS_ENABLED.load(Ordering::Relaxed) {
+        return f();
+    }
+    let start = Instant::now();
+    let result = f();
+    TIMINGS.lock().unwrap()[stage as usize] += start.elapsed().as_micros() as u64;
+    result
+}
 
-    return infer(&model_bytes, &img_bytes);
+/// Start the timings of a new inference.
+fn reset_timings() {
+    *TIMINGS.lock().unwrap() = [0; 3];
 }
 
-/// Perform the inference given the contents of the model and the image, and
-/// return the index of the predicted class.
+/// Load and optimize the model, and make it runnable.
+fn compile_model(model_bytes: &[u8], width: u32, height: u32) -> TractResult<Model> {
+    let source = load_model(model_bytes, width, height)?;
+    let input_type = source
+        .input_fact(0)?
+        .datum_type
+        .concretize()
+        .unwrap_or_else(f32::datum_type);
+    let runnable = source.clone().into_optimized()?.into_runnable()?;
+    Ok(Model {
+        runnable,
+        width,
+        height,
+        input_type,
+        source,
+        batches: RefCell::new(Vec::new()),
+    })
+}
+
+/// Get the variant of the model for batches of `size` images, compiling
+/// it the first time a batch of this size is used.
+fn batched_model(model: &Model, size: usize) -> TractResult<Rc<Runnable>> {
+    let mut batches = model.batches.borrow_mut();
+    if let Some((_, runnable)) = batches.iter().find(|(s, _)| *s == size) {
+        return Ok(runnable.clone());
+    }
+
+    let shape = tvec!(size, model.height as usize, model.width as usize, 3);
+    let runnable = Rc::new(
+        model
+            .source
+            .clone()
+            .with_input_fact(0, InferenceFact::dt_shape(model.input_type, shape))?
+            .into_optimized()?
+            .into_runnable()?,
+    );
+    batches.push((size, runnable.clone()));
+    Ok(runnable)
+}
+
+/// Perform the inference given the compiled model and the decoded
+/// image, and return the index of the predicted class, or `RUN_FAILED`.
 ///
 /// Adapted from https://github.com/sonos/tract/tree/main/examples/tensorflow-mobilenet-v2 and
 /// using the TensorFlow Mobilenet V2 model.
 /// See https://github.com/tensorflow/models/tree/master/research/slim/nets/mobilenet
-fn infer(model_bytes: &[u8], image_bytes: &[u8]) -> i32 {
-    let mut model = std::io::Cursor::new(model_bytes);
-    let model = tract_tensorflow::tensorflow()
-        .model_for_read(&mut model)
-        .unwrap()
-        .with_input_fact(
-            0,
-            InferenceFact::dt_shape(f32::datum_type(), tvec!(1, 224, 224, 3)),
-        )
-        .unwrap()
-        .into_optimized()
-        .unwrap()
-        .into_runnable()
-        .unwrap();
-
-    let image = image::load_from_memory(image_bytes).unwrap().to_rgb();
-    // The model was trained on 224 x 224 RGB images, so we are resizing the input image to this dimension.
-    let resized =
-        image::imageops::resize(&image, 224, 224, ::image::imageops::FilterType::Triangle);
-    let image: Tensor = tract_ndarray::Array4::from_shape_fn((1, 224, 224, 3), |(_, y, x, c)| {
-        resized[(x as _, y as _)][c] as f32 / 255.0
-    })
-    .into();
+fn run_model(model: &Model, image: &RgbImage) -> i32 {
+    match try_run_model(model, image) {
+        Ok(class) => class,
+        Err(_) => RUN_FAILED,
+    }
+}
+
+fn try_run_model(model: &Model, image: &RgbImage) -> TractResult<i32> {
+    let input = timed(Stage::Preprocess, || input_tensor(model, &[image]))?;
+    try_run_input(model, input)
+}
+
+/// Run the model on an input tensor built by the caller, checking that it
+/// has the shape and the type of the input of the model, instead of
+/// reading past its end.
+fn run_tensor(model: &Model, tensor_bytes: &[u8]) -> i32 {
+    let shape = (1, model.height as usize, model.width as usize, 3);
+    let len = shape.0 * shape.1 * shape.2 * shape.3;
+    if model.input_type != f32::datum_type() || tensor_bytes.len() != len * 4 {
+        return INVALID_TENSOR;
+    }
+    let values: Vec<f32> = tensor_bytes
+        .chunks_exact(4)
+        .map(|v| f32::from_le_bytes([v[0], v[1], v[2], v[3]]))
+        .collect();
+    // NaN or infinite inputs give scores that rank no class.
+    if !values.iter().all(|v| v.is_finite()) {
+        return INVALID_TENSOR;
+    }
+    let input = match tract_ndarray::Array4::from_shape_vec(shape, values) {
+        Ok(input) => input.into(),
+        Err(_) => return INVALID_TENSOR,
+    };
+    match try_run_input(model, input) {
+        Ok(class) => class,
+        Err(_) => RUN_FAILED,
+    }
+}
+
+/// Run the model on its input tensor, and return the predicted class,
+/// keeping the probability of every class for `top_k`.
+fn try_run_input(model: &Model, input: Tensor) -> TractResult<i32> {
+    let mut result = timed(Stage::Run, || model.runnable.run(tvec!(input)))?;
+    let scores = output_scores(&result.remove(0))?;
+    let (class, probabilities) = predict_class(scores)?;
+    *PROBABILITIES.lock().unwrap() = probabilities;
+    Ok(class)
+}
+
+/// Run the model on a decoded image, and return its first output.
+fn model_output(model: &Model, image: &RgbImage) -> TractResult<Arc<Tensor>> {
+    let mut result = model.runnable.run(tvec!(input_tensor(model, &[image])?))?;
+    Ok(result.remove(0))
+}
+
+/// Run the model on a batch of decoded images, and return the predicted
+/// class of each image with its probability, or an error code.
+fn run_batch(model: &Model, images: &[&RgbImage]) -> Result<Vec<(i32, f32)>, i32> {
+    if images.is_empty() {
+        return Ok(Vec::new());
+    }
+    let runnable = match images.len() {
+        1 => None,
+        size => Some(batched_model(model, size).map_err(|_| MODEL_LOAD_FAILED)?),
+    };
+
+    let run = || -> TractResult<Vec<(i32, f32)>> {
+        let input = input_tensor(model, images)?;
 
-    let result = model.run(tvec!(image)).unwrap();
-    let best = result[0]
-        .to_array_view::<f32>()
-        .unwrap()
+        let result = match &runnable {
+            Some(runnable) => runnable.run(tvec!(input))?,
+            None => model.runnable.run(tvec!(input))?,
+        };
+        // The output has one row of scores per image of the batch.
+        let scores = output_scores(&result[0])?;
+        scores
+            .chunks(scores.len() / images.len())
+            .map(|row| {
+                let (class, probabilities) = predict_class(row.to_vec())?;
+                Ok((class, probabilities[class as usize - 1]))
+            })
+            .collect()
+    };
+    run().map_err(|_| RUN_FAILED)
+}
+
+/// Build the input tensor of the model for a batch of images, with the
+/// `(N, height, width, 3)` shape.
+///
+/// Quantized models take the `u8` pixels as they are, in `[0, 255]`, and
+/// other models take `f32` pixels normalized by `set_normalization`. Models
+/// with other input types cannot be run.
+fn input_tensor(model: &Model, images: &[&RgbImage]) -> TractResult<Tensor> {
+    let shape = (images.len(), model.height as usize, model.width as usize, 3);
+    let pixels = images.iter().flat_map(|image| resized_pixels(model, image));
+    if model.input_type == u8::datum_type() {
+        return Ok(tract_ndarray::Array4::from_shape_vec(shape, pixels.collect())?.into());
+    }
+    if model.input_type != f32::datum_type() {
+        return Err(TractError::msg(format!(
+            "unsupported input type {:?}",
+            model.input_type
+        )));
+    }
+
+    // The pixels of the resized image are stored row by row, with the channels
+    // of each pixel next to each other, which is already the (1, height, width,
+    // channels) layout of the input tensor, so the buffer is converted in a
+    // single pass instead of indexing every pixel.
+    let normalization = *NORMALIZATION.lock().unwrap();
+    let values = pixels
+        .enumerate()
+        .map(|(i, v)| normalization.apply(v, i % 3))
+        .collect();
+    Ok(tract_ndarray::Array4::from_shape_vec(shape, values)?.into())
+}
+
+/// Resize an image to the input dimensions of the model, and return its pixels.
+fn resized_pixels(model: &Model, image: &RgbImage) -> Vec<u8> {
+    // The model was trained on images of a given size, such as 224 x 224 RGB images
+    // for MobileNet V2, so we are resizing the input image to this dimension.
+    if !CENTER_CROP.load(Ordering::Relaxed) {
+        return image::imageops::resize(image, model.width, model.height, resize_filter())
+            .into_raw();
+    }
+
+    // Resize the image, keeping its aspect ratio, so that the input of the
+    // model is the central part of the resized image, such as 224 x 224
+    // pixels of a 256 pixels high image for MobileNet V2.
+    let (width, height) = image.dimensions();
+    let scale = (model.width as f32 / CENTER_CROP_RATIO / width as f32)
+        .max(model.height as f32 / CENTER_CROP_RATIO / height as f32);
+    let resized_width = ((width as f32 * scale).round() as u32).max(model.width);
+    let resized_height = ((height as f32 * scale).round() as u32).max(model.height);
+    let resized = image::imageops::resize(image, resized_width, resized_height, resize_filter());
+    image::imageops::crop_imm(
+        &resized,
+        (resized_width - model.width) / 2,
+        (resized_height - model.height) / 2,
+        model.width,
+        model.height,
+    )
+    .to_image()
+    .into_raw()
+}
+
+/// Return the scores of the output of the model, as `f32` values. The
+/// output of quantized models is dequantized with the scale and the zero
+/// point set by `set_output_quantization`. Without them, as the graph does
+/// not have them, `[0, 255]` for `u8` outputs and `[-128, 127]` for `i8`
+/// outputs are mapped to `[0, 1]`.
+fn output_scores(output: &Tensor) -> TractResult<Vec<f32>> {
+    let dt = output.datum_type();
+    let quantization = *OUTPUT_QUANTIZATION.lock().unwrap();
+    let dequantize = |q: i32, default_zero_point: i32| {
+        let (scale, zero_point) = quantization.unwrap_or((1.0 / 255.0, default_zero_point));
+        // Values below the zero point have no probability.
+        ((q - zero_point) as f32 * scale).max(0.0)
+    };
+    if dt == u8::datum_type() {
+        Ok(output
+            .to_array_view::<u8>()?
+            .iter()
+            .map(|v| dequantize(*v as i32, 0))
+            .collect())
+    } else if dt == i8::datum_type() {
+        Ok(output
+            .to_array_view::<i8>()?
+            .iter()
+            .map(|v| dequantize(*v as i32, -128))
+            .collect())
+    } else {
+        Ok(output.to_array_view::<f32>()?.iter().cloned().collect())
+    }
+}
+
+/// Return the filter set by `set_resize_filter`.
+fn resize_filter() -> FilterType {
+    match RESIZE_FILTER.load(Ordering::Relaxed) {
+        0 => FilterType::Nearest,
+        2 => FilterType::CatmullRom,
+        3 => FilterType::Gaussian,
+        4 => FilterType::Lanczos3,
+        _ => FilterType::Triangle,
+    }
+}
+
+/// Pick the predicted class from the output of the model for one image,
+/// and return it with the probability of every class.
+fn predict_class(output: Vec<f32>) -> TractResult<(i32, Vec<f32>)> {
+    // The prior and the mask are applied to the logits, which are the log
+    // of the output of models whose output is already probabilities.
+    let mut scores = if OUTPUT_PROBABILITIES.load(Ordering::Relaxed) {
+        output.into_iter().map(|p| p.ln()).collect()
+    } else {
+        output
+    };
+    for (score, prior) in scores.iter_mut().zip(CLASS_PRIOR.lock().unwrap().iter()) {
+        *score += prior;
+    }
+    if SKIP_BACKGROUND.load(Ordering::Relaxed) {
+        if let Some(background) = scores.first_mut() {
+            *background = f32::NEG_INFINITY;
+        }
+    }
+    let best = scores
         .iter()
         .cloned()
         .zip(1..)
-        .max_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .ok_or_else(|| TractError::msg("the model has no output scores"))?;
 
-    return best.unwrap().1;
+    Ok((best.1, softmax(&scores)))
+}
+
+/// Turn logits into probabilities that sum to 1.
+fn softmax(scores: &[f32]) -> Vec<f32> {
+    let max = scores.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let exps: Vec<f32> = scores.iter().map(|s| (s - max).exp()).collect();
+    let sum: f32 = exps.iter().sum();
+    exps.into_iter().map(|e| e / sum).collect()
+}
+
+/// Load the TensorFlow model, with a `width` x `height` RGB image as its input.
+///
+/// The input is the one selected by `set_input_name`, or the only input of
+/// the graph.
+///
+/// The type of the input is the one of the placeholder of the graph, either
+/// `f32`, or `u8` for quantized models, and is `f32` if the graph does not
+/// specify it. Models with another input type cannot be loaded.
+fn load_model(model_bytes: &[u8], width: u32, height: u32) -> TractResult<InferenceModel> {
+    let mut model = read_graph(model_bytes)?;
+    select_input(&mut model)?;
+    let input_type = match model.input_fact(0)?.datum_type.concretize() {
+        Some(dt) if dt == u8::datum_type() => dt,
+        Some(dt) if dt != f32::datum_type() => {
+            return Err(TractError::msg(format!("unsupported input type {:?}", dt)))
+        }
+        _ => f32::datum_type(),
+    };
+    model.with_input_fact(
+        0,
+        InferenceFact::dt_shape(input_type, tvec!(1, height as usize, width as usize, 3)),
+    )
+}
+
+/// Read the graph of a TensorFlow model, without setting its input.
+fn read_graph(model_bytes: &[u8]) -> TractResult<InferenceModel> {
+    let mut model = std::io::Cursor::new(model_bytes);
+    tract_tensorflow::tensorflow().model_for_read(&mut model)
+}
+
+/// Make the input selected by `set_input_name` the only input of the model,
+/// or check that the model has a single input when none is selected. The
+/// selected input must be the only one the first output of the model
+/// depends on.
+fn select_input(model: &mut InferenceModel) -> TractResult<()> {
+    let name = INPUT_NAME.lock().unwrap().clone();
+    let inputs = input_names(model)?;
+    if name.is_empty() {
+        if inputs.len() == 1 {
+            return Ok(());
+        }
+        return Err(TractError::new(UnknownInput(format!(
+            "the model has {} inputs, select one of {}",
+            inputs.len(),
+            inputs.join(", ")
+        ))));
+    }
+    if !inputs.contains(&name) {
+        return Err(TractError::new(UnknownInput(format!(
+            "{} is not an input of the model, select one of {}",
+            name,
+            inputs.join(", ")
+        ))));
+    }
+    // The other inputs are not fed, so only the nodes of the first output,
+    // which is the only one that is read, are kept.
+    let output = model.output_outlets()?[0];
+    model.set_input_names([name])?;
+    model.set_output_outlets(&[output])
+}
+
+/// Return the names of the nodes of the inputs of the model.
+fn input_names(model: &InferenceModel) -> TractResult<Vec<String>> {
+    Ok(model
+        .input_outlets()?
+        .iter()
+        .map(|outlet| model.node(outlet.node).name.clone())
+        .collect())
 }
 
 /// If running in Node's WASI runtime, a `_start` function
 /// is required for instantiating the module.
+///
+/// # Safety
+///
+/// The function does not do anything, and is always safe to call.
+#[cfg(target_arch = "wasm32")]
 #[no_mangle]
 pub unsafe extern "C" fn _start() {}
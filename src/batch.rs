@@ -0,0 +1,312 @@
+//! Run the inference on all the images of a `multipart/form-data` request
+//! at once, with a batch of images as the input of the model.
+
+use std::{
+    convert::Infallible,
+    time::{Duration, Instant},
+};
+
+use hyper::{
+    body::Bytes,
+    header::{HeaderValue, CONTENT_TYPE},
+    http::request::Parts,
+    Body, Response,
+};
+use serde::Serialize;
+use tracing::Span;
+use wasmtime::{Instance, Val};
+
+use crate::{
+    check_dimensions, decoded_image_too_large, free_guest_memory, gzip, log_prediction,
+    model_load_failed, models::RegisteredModel, needs_dimensions_check, read_guest_memory,
+    set_normalization, unknown_model_handle, with_retries, write_guest_memory, ClientError, Opts,
+    PredictParams, Prediction, ResponseFormat, State, DECODE_FAILED, IMAGE_TOO_LARGE,
+    INFER_BATCH_FN, INVALID_BATCH, MODEL_LOAD_FAILED, RUN_FAILED, UNKNOWN_MODEL,
+};
+
+/// Result of the inference on one image of a batch.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum BatchItem {
+    Prediction(Prediction),
+    Error { error: String },
+}
+
+/// Body of the response to clients that accept JSON, with one item per
+/// image, in the order of the request.
+#[derive(Serialize)]
+struct BatchResponse {
+    predictions: Vec<BatchItem>,
+    inference_ms: u64,
+}
+
+/// Return the boundary of a `multipart/form-data` request, or `None` for
+/// any other request.
+pub fn multipart_boundary(parts: &Parts) -> Option<String> {
+    let content_type = parts.headers.get(CONTENT_TYPE)?.to_str().ok()?;
+    if !content_type
+        .trim()
+        .to_ascii_lowercase()
+        .starts_with("multipart/form-data")
+    {
+        return None;
+    }
+    // A multipart request without a boundary cannot be parsed, and is then
+    // rejected when reading the images.
+    Some(multer::parse_boundary(content_type).unwrap_or_default())
+}
+
+/// Name of the field of a `multipart/form-data` request that selects its
+/// model, instead of being an image.
+const MODEL_FIELD: &str = "model";
+
+/// Name of the field of a `multipart/form-data` request with its images,
+/// which are the parts of this field and the parts that are files.
+const FILE_FIELD: &str = "file";
+
+/// The images of a `multipart/form-data` request, and the name of the model
+/// of its `model` field, if any.
+pub struct Form {
+    pub images: Vec<Vec<u8>>,
+    pub model: Option<String>,
+}
+
+/// Read the images of a `multipart/form-data` request, such as a form with
+/// one or several `file` fields, one per part. A `model` part that is not a
+/// file selects the model instead, and the other parts that are not files,
+/// such as the submit button of a form, are ignored.
+pub async fn read_images(
+    parts: &Parts,
+    mut body: Body,
+    boundary: String,
+    opts: &Opts,
+) -> Result<Form, anyhow::Error> {
+    let invalid = |err: multer::Error| {
+        ClientError::bad_request(format!("invalid multipart/form-data body: {}", err))
+    };
+    if boundary.is_empty() {
+        return Err(ClientError::bad_request("missing multipart/form-data boundary").into());
+    }
+
+    let data =
+        gzip::read_body(parts, &mut body, opts.max_image_bytes * opts.max_batch_size).await?;
+    let stream = futures::stream::once(async { Ok::<_, Infallible>(Bytes::from(data)) });
+    let mut multipart = multer::Multipart::new(stream, boundary);
+
+    let mut images = Vec::new();
+    let mut model = None;
+    while let Some(field) = multipart.next_field().await.map_err(invalid)? {
+        if field.name() == Some(MODEL_FIELD) && field.file_name().is_none() {
+            let name = field.text().await.map_err(invalid)?;
+            // Forms send an empty field when no model is selected.
+            model = Some(name.trim().to_string()).filter(|name| !name.is_empty());
+            continue;
+        }
+        if field.file_name().is_none() && field.name() != Some(FILE_FIELD) {
+            continue;
+        }
+        let image = field.bytes().await.map_err(invalid)?;
+        if image.len() > opts.max_image_bytes {
+            return Err(ClientError::unprocessable(format!(
+                "image {} is larger than {} bytes",
+                images.len() + 1,
+                opts.max_image_bytes
+            ))
+            .into());
+        }
+        images.push(image.to_vec());
+        if images.len() > opts.max_batch_size {
+            return Err(ClientError::bad_request(format!(
+                "expected at most {} images",
+                opts.max_batch_size
+            ))
+            .into());
+        }
+    }
+    if images.is_empty() {
+        return Err(ClientError::bad_request("expected at least one image").into());
+    }
+    Ok(Form { images, model })
+}
+
+/// Run the model on a batch of images, and respond with one prediction per
+/// image, returning the duration of the inference with the response.
+/// Images that cannot be used get an error instead of a prediction,
+/// without failing the other images of the batch.
+pub fn get_batch_prediction(
+    images: &[Vec<u8>],
+    state: &State,
+    model: &RegisteredModel,
+    params: &PredictParams,
+) -> Result<(Response<Body>, Duration), anyhow::Error> {
+    if params.random_crop_seed.is_some() || params.format != ResponseFormat::Label {
+        return Err(ClientError::bad_request(
+            "random crops and the raw formats are not supported for several images",
+        )
+        .into());
+    }
+    if params.top_k.is_some() || params.full {
+        return Err(
+            ClientError::bad_request("topk and full are not supported for several images").into(),
+        );
+    }
+
+    let (results, duration) = with_retries(state, model, |instance, handle| {
+        infer_batch(instance, model, handle, images, &state.opts, params)
+    })?;
+    state.metrics.inference(
+        results.iter().filter(|result| result.is_ok()).count(),
+        duration,
+    );
+
+    let labels = model.labels(&state.opts, &params.languages);
+    let mut predictions = Vec::new();
+    for (result, image) in results.into_iter().zip(images) {
+        predictions.push(match result {
+            Ok((index, score)) => {
+                let label = labels.get(index, &state.opts)?;
+                log_prediction(
+                    model,
+                    index,
+                    Some(&label),
+                    Some(score),
+                    image.len(),
+                    duration,
+                );
+                BatchItem::Prediction(Prediction {
+                    label,
+                    index,
+                    score,
+                })
+            }
+            Err(err) => BatchItem::Error {
+                error: err.to_string(),
+            },
+        });
+    }
+
+    if params.json {
+        let body = BatchResponse {
+            predictions,
+            inference_ms: duration.as_millis() as u64,
+        };
+        let mut res = Response::new(Body::from(serde_json::to_vec(&body)?));
+        res.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        return Ok((res, duration));
+    }
+
+    // Respond with one line per image, with either its label or the
+    // reason why it could not be classified.
+    let lines: Vec<String> = predictions
+        .into_iter()
+        .map(|item| match item {
+            BatchItem::Prediction(prediction) => prediction.label,
+            BatchItem::Error { error } => format!("error: {}", error),
+        })
+        .collect();
+    Ok((Response::new(Body::from(lines.join("\n"))), duration))
+}
+
+/// Run the model, loaded with `load_model`, on a batch of images in an
+/// existing instance, and return the predicted class of each image with
+/// its probability, or the reason why it could not be classified, with
+/// the duration of the inference.
+#[allow(clippy::type_complexity)]
+fn infer_batch(
+    instance: &Instance,
+    model: &RegisteredModel,
+    handle: i32,
+    images: &[Vec<u8>],
+    opts: &Opts,
+    params: &PredictParams,
+) -> Result<(Vec<Result<(usize, f32), ClientError>>, Duration), anyhow::Error> {
+    let start = Instant::now();
+
+    // Images rejected by the dimension checks are left out of the batch.
+    let mut checked = Vec::new();
+    for img_bytes in images {
+        let check = if needs_dimensions_check(opts) {
+            let img_bytes_ptr = write_guest_memory(img_bytes, instance)?;
+            let check = check_dimensions(instance, img_bytes_ptr, img_bytes.len(), opts);
+            free_guest_memory(img_bytes_ptr, img_bytes.len(), instance)?;
+            check
+        } else {
+            Ok(())
+        };
+        match check {
+            Ok(()) => checked.push(Ok(img_bytes)),
+            Err(err) => checked.push(Err(err.downcast::<ClientError>()?)),
+        }
+    }
+
+    // The module reads the images one after the other, each prefixed by
+    // its length as a little-endian u32.
+    let accepted: Vec<&Vec<u8>> = checked
+        .iter()
+        .filter_map(|c| c.as_ref().ok())
+        .cloned()
+        .collect();
+    let mut packed = Vec::new();
+    for img_bytes in &accepted {
+        packed.extend_from_slice(&(img_bytes.len() as u32).to_le_bytes());
+        packed.extend_from_slice(img_bytes);
+    }
+
+    set_normalization(instance, model, params)?;
+    let packed_ptr = write_guest_memory(&packed, instance)?;
+    let out_len = accepted.len() * 8;
+    let out_ptr = write_guest_memory(&vec![0; out_len], instance)?;
+    let results = instance
+        .get_func(INFER_BATCH_FN)
+        .expect("expected batch inference function not found")
+        .call(&[
+            Val::from(handle),
+            Val::from(packed_ptr as i32),
+            Val::from(packed.len() as i32),
+            Val::from(out_ptr as i32),
+        ])?;
+    free_guest_memory(packed_ptr, packed.len(), instance)?;
+    let out = read_guest_memory(instance, out_ptr, out_len)?;
+    free_guest_memory(out_ptr, out_len, instance)?;
+    let duration = start.elapsed();
+    Span::current().record("inference_ms", duration.as_millis() as u64);
+    tracing::debug!(images = accepted.len(), ?duration, "inference time");
+
+    match results.first() {
+        Some(Val::I32(UNKNOWN_MODEL)) => return Err(unknown_model_handle(model).into()),
+        // The images are packed by the server, not by the client.
+        Some(Val::I32(INVALID_BATCH)) => {
+            return Err(ClientError::internal("invalid batch of images").into())
+        }
+        Some(Val::I32(MODEL_LOAD_FAILED)) => return Err(model_load_failed(model).into()),
+        Some(Val::I32(RUN_FAILED)) => {
+            return Err(ClientError::internal("cannot run the model on the images").into())
+        }
+        Some(Val::I32(len)) if *len as usize == accepted.len() => {}
+        _ => return Err(ClientError::internal("cannot get the batch predictions").into()),
+    }
+
+    // The module writes pairs of an i32 class, or an error code, and an
+    // f32 probability, for each image of the batch.
+    let mut predictions = out.chunks(8).map(|pair| {
+        let class = i32::from_le_bytes([pair[0], pair[1], pair[2], pair[3]]);
+        let probability = f32::from_le_bytes([pair[4], pair[5], pair[6], pair[7]]);
+        match class {
+            DECODE_FAILED => Err(ClientError::bad_request("cannot decode the image")),
+            IMAGE_TOO_LARGE => Err(decoded_image_too_large(opts)),
+            class if class >= 0 => Ok((class as usize, probability)),
+            _ => Err(ClientError::internal("cannot run the model on the image")),
+        }
+    });
+    let results = checked
+        .into_iter()
+        .map(|check| match check {
+            Ok(_) => predictions
+                .next()
+                .unwrap_or_else(|| Err(ClientError::internal("missing prediction"))),
+            Err(err) => Err(err),
+        })
+        .collect();
+    Ok((results, duration))
+}
@@ -0,0 +1,396 @@
+//! Run a model on images from other programs, without the server, such as:
+//!
+//! ```no_run
+//! use wasi_tensorflow_inference::InferenceEngine;
+//!
+//! let engine = InferenceEngine::new(
+//!     "model/mobilenet_v2_1.4_224_frozen.pb",
+//!     "model/labels.txt",
+//! )?;
+//! let prediction = engine.predict(&std::fs::read("testdata/husky.jpeg")?)?;
+//! println!("{} ({:?})", prediction.label, prediction.inference_time);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use std::{path::Path, sync::Arc, time::Duration};
+
+use wasmtime::Engine;
+
+use crate::{
+    check_classes, check_files, compile_module, download_model, engine_config, infer_image,
+    is_model_url, load_classes,
+    metrics::Metrics,
+    model_inputs,
+    models::{LabelMapping, RegisteredModel, SelectedInput, DEFAULT_MODEL},
+    pool::InstancePool,
+    read_file_bytes, top_k, Opts, PredictParams,
+};
+
+/// The module, compiled once, with a model and its labels, which runs the
+/// model on images with the default options of the server.
+///
+/// The module is read from `./model/optimized-wasi.wasm`, or the `--wasm`
+/// path of the options of `from_opts`, and the instances in which the model
+/// is compiled are reused by the following predictions on the same thread.
+pub struct InferenceEngine {
+    model: RegisteredModel,
+    pool: InstancePool,
+    opts: Arc<Opts>,
+}
+
+/// The predicted class of an image.
+#[derive(Debug, Clone)]
+pub struct Prediction {
+    pub label: String,
+    /// Index of the class, which is the line number of its label, plus the
+    /// label offset.
+    pub index: usize,
+    /// Probability of the class, between 0 and 1.
+    pub score: f32,
+    /// Time spent decoding the image and running the model.
+    pub inference_time: Duration,
+}
+
+impl InferenceEngine {
+    /// Compile the module, and load a model taking a single RGB image as
+    /// its only input, such as the bundled MobileNet V2 model, with the
+    /// labels of its classes, one per line. The images are resized to the
+    /// dimensions of the input in the graph of the model, or to 224 x 224
+    /// pixels if the graph does not specify them.
+    ///
+    /// The first class of the model is a background class, as for
+    /// MobileNet V2, and the labels file has a line for it. The output of
+    /// the model is probabilities, such as the softmax of MobileNet V2.
+    pub fn new(
+        model_path: impl AsRef<Path>,
+        labels_path: impl AsRef<Path>,
+    ) -> Result<Self, anyhow::Error> {
+        Self::with_label_offset(model_path, labels_path, 0)
+    }
+
+    /// Same as `new`, for a labels file whose first line is the label of
+    /// the class after the first `label_offset` classes of the model, such
+    /// as 1 for a MobileNet labels file without the background class.
+    ///
+    /// The other options are the ones of the server started without
+    /// arguments, which do not depend on its environment variables, such
+    /// as `WASM_PATH`. `from_opts` takes other options.
+    pub fn with_label_offset(
+        model_path: impl AsRef<Path>,
+        labels_path: impl AsRef<Path>,
+        label_offset: usize,
+    ) -> Result<Self, anyhow::Error> {
+        let opts = Arc::new(Opts {
+            model: model_path.as_ref().display().to_string(),
+            labels: labels_path.as_ref().display().to_string(),
+            ..Opts::default()
+        });
+        check_files(&opts)?;
+        // The path of the model is also its name, which is how the
+        // instances of the pool are found.
+        Self::load(
+            opts.model.clone(),
+            &opts.model,
+            opts.labels.clone(),
+            None,
+            LabelMapping {
+                label_offset,
+                has_background: true,
+            },
+            opts.clone(),
+        )
+    }
+
+    /// Load the default model of the server, with the options of the
+    /// server, such as the paths of the model, its labels and the module,
+    /// the preprocessing of the images, or the settings of Wasmtime, as for
+    /// the `infer` command:
+    ///
+    /// ```no_run
+    /// use std::sync::Arc;
+    ///
+    /// use structopt::StructOpt;
+    /// use wasi_tensorflow_inference::{InferenceEngine, Opts};
+    ///
+    /// let opts = Opts::from_iter(["wasi-tensorflow-inference", "--preprocess", "center-crop"]);
+    /// let engine = InferenceEngine::from_opts(Arc::new(opts))?;
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn from_opts(opts: Arc<Opts>) -> Result<Self, anyhow::Error> {
+        check_files(&opts)?;
+        Self::load(
+            DEFAULT_MODEL.to_string(),
+            &opts.model,
+            opts.labels.clone(),
+            opts.input_name.clone(),
+            LabelMapping::default_model(&opts),
+            opts.clone(),
+        )
+    }
+
+    fn load(
+        name: String,
+        model_path: &str,
+        labels_path: String,
+        input_name: Option<String>,
+        mapping: LabelMapping,
+        opts: Arc<Opts>,
+    ) -> Result<Self, anyhow::Error> {
+        let engine = Engine::new(&engine_config(&opts)?);
+        let module = compile_module(&engine, &opts)?;
+
+        let model_bytes = if is_model_url(model_path) {
+            download_model(model_path, &opts)?
+        } else {
+            read_file_bytes(model_path)
+                .map_err(|err| anyhow::Error::msg(format!("cannot read {}: {}", model_path, err)))?
+        };
+        let inputs = model_inputs(&module, &model_bytes, &opts)?;
+        let input = SelectedInput::select(&name, &inputs, input_name, None)?;
+        let mut model = RegisteredModel::new(
+            name,
+            Arc::new(model_bytes),
+            input,
+            opts.normalization,
+            labels_path,
+            mapping,
+            &opts,
+        )?;
+        // As for the normalization, the output is the one of the options,
+        // whatever the path of the model.
+        model.output = opts.model_output;
+        // The labels and the class prior are checked as when starting the
+        // server.
+        model.classes = load_classes(&module, &model, &opts)?;
+        check_classes(&model, &opts)?;
+
+        Ok(InferenceEngine {
+            model,
+            pool: InstancePool::new(module, opts.clone(), Arc::new(Metrics::new())),
+            opts,
+        })
+    }
+
+    /// Run the model on an encoded image, such as a JPEG or PNG file, and
+    /// return its predicted class.
+    pub fn predict(&self, image_bytes: &[u8]) -> Result<Prediction, anyhow::Error> {
+        let params = PredictParams::default();
+        let (index, inference_time, top) =
+            self.pool.with_instance(&self.model, |instance, handle| {
+                let (index, duration) = infer_image(
+                    instance,
+                    &self.model,
+                    handle,
+                    image_bytes,
+                    &self.opts,
+                    &params,
+                )?;
+                Ok((index, duration, top_k(instance, 1)?))
+            })?;
+        let score = match top.first() {
+            Some((class, score)) if *class == index => *score,
+            _ => return Err(anyhow::Error::msg("cannot get the score of the prediction")),
+        };
+
+        Ok(Prediction {
+            label: self.model.labels(&self.opts, &[]).get(index, &self.opts)?,
+            index,
+            score,
+            inference_time,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use structopt::StructOpt;
+    use wasmtime::{Instance, Val};
+
+    use crate::{read_guest_memory, ClientError, GuestBuffer, MEMORY};
+
+    use super::*;
+
+    const HUSKY: &[u8] = include_bytes!("../testdata/husky.jpeg");
+    const GOLDEN_RETRIEVER: &[u8] = include_bytes!("../testdata/golden-retriever.jpeg");
+
+    /// Load the default model with the options of the server given as
+    /// command line arguments.
+    fn engine(args: &[&str]) -> InferenceEngine {
+        let opts = Opts::from_iter(
+            std::iter::once("wasi-tensorflow-inference").chain(args.iter().copied()),
+        );
+        InferenceEngine::from_opts(Arc::new(opts)).unwrap()
+    }
+
+    /// Return the error of loading the default model with the options of
+    /// the server given as command line arguments.
+    fn engine_error(args: &[&str]) -> String {
+        let opts = Opts::from_iter(
+            std::iter::once("wasi-tensorflow-inference").chain(args.iter().copied()),
+        );
+        match InferenceEngine::from_opts(Arc::new(opts)) {
+            Ok(_) => panic!("the model is loaded with {:?}", args),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    /// Return the size of the linear memory of the instance of the pool.
+    fn memory_size(engine: &InferenceEngine) -> usize {
+        engine
+            .pool
+            .with_instance(&engine.model, |instance, _| {
+                Ok(instance.get_memory(MEMORY).unwrap().data_size())
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn runs_several_inferences_in_the_same_instance() {
+        let engine = engine(&[]);
+        let params = PredictParams::default();
+        let predictions = engine
+            .pool
+            .with_instance(&engine.model, |instance, handle| {
+                let mut predictions = Vec::new();
+                for image in [HUSKY, GOLDEN_RETRIEVER, HUSKY, GOLDEN_RETRIEVER] {
+                    let (index, _) = infer_image(
+                        instance,
+                        &engine.model,
+                        handle,
+                        image,
+                        &engine.opts,
+                        &params,
+                    )?;
+                    predictions.push((index, top_k(instance, 1)?[0].1));
+                }
+                Ok(predictions)
+            })
+            .unwrap();
+
+        // The module only borrows the model and the images written by the
+        // host, so the inferences that follow are not affected by them.
+        assert_eq!(predictions[0].0, 250);
+        assert_eq!(predictions[1].0, 209);
+        assert_eq!(predictions[2], predictions[0]);
+        assert_eq!(predictions[3], predictions[1]);
+    }
+
+    /// Run the label inference of the module on an image, after setting
+    /// its labels from a labels file and its offset, if any.
+    fn guest_label(
+        instance: &Instance,
+        handle: i32,
+        labels: Option<(&str, usize)>,
+        image: &[u8],
+    ) -> Result<String, anyhow::Error> {
+        if let Some((file, offset)) = labels {
+            let labels = GuestBuffer::write(&std::fs::read(file)?, instance)?;
+            instance.get_func("set_labels").unwrap().call(&[
+                Val::from(labels.ptr as i32),
+                Val::from(labels.len as i32),
+                Val::from(offset as i32),
+            ])?;
+        }
+        let image = GuestBuffer::write(image, instance)?;
+        let out = GuestBuffer::write(&[0; 256], instance)?;
+        let len = instance
+            .get_func("infer_label_with_handle")
+            .unwrap()
+            .call(&[
+                Val::from(handle),
+                Val::from(image.ptr as i32),
+                Val::from(image.len as i32),
+                Val::from(out.ptr as i32),
+                Val::from(out.len as i32),
+            ])?[0]
+            .unwrap_i32();
+        assert!(len >= 0, "error {}", len);
+        let label = read_guest_memory(instance, out.ptr, len as usize)?;
+        Ok(String::from_utf8(label)?)
+    }
+
+    #[test]
+    fn resolves_the_labels_in_the_module_with_their_offset() {
+        let engine = engine(&[]);
+        let labels = engine
+            .pool
+            .with_instance(&engine.model, |instance, handle| {
+                Ok([
+                    guest_label(instance, handle, None, HUSKY)?,
+                    guest_label(
+                        instance,
+                        handle,
+                        Some(("tests/fixtures/labels-without-background.txt", 1)),
+                        HUSKY,
+                    )?,
+                    // Without the offset, the labels are those of the next
+                    // classes.
+                    guest_label(
+                        instance,
+                        handle,
+                        Some(("tests/fixtures/labels-without-background.txt", 0)),
+                        HUSKY,
+                    )?,
+                ])
+            })
+            .unwrap();
+        assert_eq!(
+            labels,
+            [
+                "Eskimo dog, husky",
+                "Eskimo dog, husky",
+                "malamute, malemute, Alaskan malamute"
+            ]
+        );
+    }
+
+    #[test]
+    fn checks_the_labels_and_the_class_prior_as_the_server() {
+        assert_eq!(
+            engine_error(&[
+                "--labels",
+                "tests/fixtures/labels-without-background.txt",
+                "--labels-check",
+                "strict",
+            ]),
+            "tests/fixtures/labels-without-background.txt has 1000 labels, but the model has \
+             1001 classes, set the label offset to 1 if the file has no line for the background \
+             class (see the readme)"
+        );
+
+        let prior = std::env::temp_dir().join(format!(
+            "wasi-tensorflow-inference-{}-engine-prior.txt",
+            std::process::id()
+        ));
+        std::fs::write(&prior, "0\n-1\n").unwrap();
+        let err = engine_error(&["--class-prior", prior.to_str().unwrap()]);
+        std::fs::remove_file(&prior).unwrap();
+        assert_eq!(
+            err,
+            "the class prior has 2 values, but the model has 1001 classes"
+        );
+    }
+
+    #[test]
+    fn frees_the_images_it_rejects() {
+        let engine = engine(&["--min-dimension", "1000"]);
+        // The bytes after the end of the image are ignored, and make the
+        // image large enough for the memory to grow if it is not freed.
+        let mut image = HUSKY.to_vec();
+        image.resize(4 << 20, 0);
+        let reject = || {
+            let err = engine.predict(&image).unwrap_err();
+            let err = err.downcast_ref::<ClientError>().unwrap();
+            assert_eq!(err.status, hyper::StatusCode::UNPROCESSABLE_ENTITY);
+        };
+
+        // All the requests run in the same instance, of the pool of the
+        // current thread.
+        reject();
+        let size = memory_size(&engine);
+        for _ in 0..16 {
+            reject();
+        }
+        assert_eq!(memory_size(&engine), size);
+    }
+}
@@ -0,0 +1,134 @@
+//! Decompress the bodies of requests sent with `Content-Encoding: gzip`, and
+//! compress the responses of clients that send `Accept-Encoding: gzip`.
+
+use std::io::{self, Write};
+
+use flate2::{write::GzDecoder, write::GzEncoder, Compression};
+use hyper::{
+    body::HttpBody as _,
+    header::{HeaderMap, HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, VARY},
+    http::request::Parts,
+    Body, Response,
+};
+
+use crate::{image_too_large, read_with_limit, ClientError};
+
+/// Read the body of a request, decompressing it when it is sent with
+/// `Content-Encoding: gzip`. Compressed bodies are decompressed as they are
+/// received, and rejected as soon as they are larger than `max_bytes` once
+/// decompressed, so that small bodies cannot expand into large images.
+pub async fn read_body(
+    parts: &Parts,
+    body: &mut Body,
+    max_bytes: usize,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let encoding = match parts.headers.get(CONTENT_ENCODING) {
+        Some(encoding) => encoding
+            .to_str()
+            .map(|encoding| encoding.trim().to_ascii_lowercase())
+            .unwrap_or_default(),
+        None => return read_with_limit(body, max_bytes).await,
+    };
+    match encoding.as_str() {
+        "identity" => read_with_limit(body, max_bytes).await,
+        "gzip" | "x-gzip" => decompress(body, max_bytes).await,
+        _ => Err(ClientError::unsupported_media_type(format!(
+            "unsupported content encoding {:?}, expected gzip",
+            encoding
+        ))
+        .into()),
+    }
+}
+
+async fn decompress(body: &mut Body, max_bytes: usize) -> Result<Vec<u8>, anyhow::Error> {
+    let mut decoder = GzDecoder::new(LimitedWriter {
+        buf: Vec::new(),
+        max_bytes,
+        exceeded: false,
+    });
+    let invalid = |decoder: &GzDecoder<LimitedWriter>, err: io::Error| {
+        if decoder.get_ref().exceeded {
+            image_too_large(max_bytes)
+        } else {
+            ClientError::bad_request(format!("invalid gzip body: {}", err))
+        }
+    };
+    while let Some(next) = body.data().await {
+        let chunk = next?;
+        if let Err(err) = decoder.write_all(&chunk) {
+            return Err(invalid(&decoder, err).into());
+        }
+    }
+    // A body that ends before the end of the gzip stream is invalid.
+    if let Err(err) = decoder.try_finish() {
+        return Err(invalid(&decoder, err).into());
+    }
+    Ok(decoder.finish()?.buf)
+}
+
+/// The decompressed body, which fails to write more than `max_bytes`.
+struct LimitedWriter {
+    buf: Vec<u8>,
+    max_bytes: usize,
+    exceeded: bool,
+}
+
+impl Write for LimitedWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.buf.len() + data.len() > self.max_bytes {
+            self.exceeded = true;
+            return Err(io::Error::other("the body is too large"));
+        }
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Return whether the `Accept-Encoding` header of a request accepts gzip,
+/// such as `gzip`, `gzip;q=0.8` or `*`, but not `gzip;q=0`.
+pub fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let coding = coding.to_ascii_lowercase();
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+            let refused = params
+                .filter_map(|param| param.strip_prefix("q="))
+                .any(|q| q.parse::<f32>().ok() == Some(0.0));
+            matches!(name, "gzip" | "x-gzip" | "*") && !refused
+        })
+}
+
+/// Compress the body of a response with gzip, when `accepted` by the client
+/// and the body is not already encoded. Responses whose encoding depends on
+/// the request say so with `Vary: Accept-Encoding`, for caches.
+pub async fn compress(
+    res: Response<Body>,
+    accepted: bool,
+) -> Result<Response<Body>, anyhow::Error> {
+    let (mut parts, body) = res.into_parts();
+    parts
+        .headers
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+    if !accepted || parts.headers.contains_key(CONTENT_ENCODING) {
+        return Ok(Response::from_parts(parts, body));
+    }
+
+    let data = hyper::body::to_bytes(body).await?;
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&data)?;
+    let compressed = encoder.finish()?;
+    parts
+        .headers
+        .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    parts.headers.remove(CONTENT_LENGTH);
+    Ok(Response::from_parts(parts, Body::from(compressed)))
+}
@@ -0,0 +1,54 @@
+//! Keep the images downloaded from a URL, so that requests for the same URL,
+//! such as when testing the server, do not download it again.
+
+use std::{collections::VecDeque, sync::Mutex};
+
+/// Images downloaded by the server, by URL, least recently used first out.
+pub struct ImageCache {
+    max_entries: usize,
+    max_bytes: usize,
+    /// Most recently used first.
+    entries: Mutex<VecDeque<(String, Vec<u8>)>>,
+}
+
+impl ImageCache {
+    /// Create a cache of up to `max_entries` images, with up to `max_bytes`
+    /// for all of them. A cache of 0 entries is disabled.
+    pub fn new(max_entries: usize, max_bytes: usize) -> Self {
+        ImageCache {
+            max_entries,
+            max_bytes,
+            entries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Return the image downloaded from `url`, if it is in the cache.
+    pub fn get(&self, url: &str) -> Option<Vec<u8>> {
+        let mut entries = self.entries.lock().expect("the image cache is poisoned");
+        let i = entries.iter().position(|(u, _)| u == url)?;
+        let entry = entries.remove(i)?;
+        let image = entry.1.clone();
+        entries.push_front(entry);
+        Some(image)
+    }
+
+    /// Add the image downloaded from `url`, removing the least recently used
+    /// images to make room for it. Images larger than the whole cache are
+    /// not added.
+    pub fn insert(&self, url: &str, image: &[u8]) {
+        if self.max_entries == 0 || image.len() > self.max_bytes {
+            return;
+        }
+        let mut entries = self.entries.lock().expect("the image cache is poisoned");
+        entries.retain(|(u, _)| u != url);
+        entries.push_front((url.to_string(), image.to_vec()));
+
+        let mut total: usize = entries.iter().map(|(_, image)| image.len()).sum();
+        while entries.len() > self.max_entries || total > self.max_bytes {
+            match entries.pop_back() {
+                Some((_, image)) => total -= image.len(),
+                None => break,
+            }
+        }
+    }
+}
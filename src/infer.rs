@@ -0,0 +1,45 @@
+//! Run the inference on a single image from the command line, such as in
+//! scripts or CI checks, without starting the server.
+
+use std::{fs, path::PathBuf, sync::Arc};
+
+use serde_json::json;
+use structopt::StructOpt;
+
+use crate::{InferenceEngine, Opts};
+
+/// Options of the `infer` command.
+#[derive(Debug, StructOpt)]
+pub struct InferOpts {
+    /// Image to classify, such as a JPEG file.
+    #[structopt(long)]
+    image: PathBuf,
+
+    /// Print the prediction as JSON, with its index, score and inference
+    /// time, instead of only its label.
+    #[structopt(long)]
+    json: bool,
+}
+
+/// Load the default model with the options of the server, run it on the
+/// image, and print its prediction.
+pub fn run(cmd: &InferOpts, opts: Arc<Opts>) -> Result<(), anyhow::Error> {
+    let image = fs::read(&cmd.image).map_err(|err| {
+        anyhow::Error::msg(format!("cannot read {}: {}", cmd.image.display(), err))
+    })?;
+    let engine = InferenceEngine::from_opts(opts)?;
+    let prediction = engine.predict(&image)?;
+
+    if cmd.json {
+        let prediction = json!({
+            "label": prediction.label,
+            "index": prediction.index,
+            "score": prediction.score,
+            "inference_ms": prediction.inference_time.as_millis() as u64,
+        });
+        println!("{}", prediction);
+    } else {
+        println!("{}", prediction.label);
+    }
+    Ok(())
+}
@@ -0,0 +1,3760 @@
+//! Run TensorFlow models compiled in a WASI module with Wasmtime, either
+//! from the inference server of `main.rs`, or from other programs, with
+//! `InferenceEngine`.
+
+use std::{
+    collections::HashSet,
+    fmt,
+    fs::{metadata, File},
+    io::{BufRead, IsTerminal, Read},
+    net::SocketAddr,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use hyper::header::{
+    HeaderValue, ACCEPT, ACCEPT_LANGUAGE, ALLOW, CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER,
+};
+use hyper::http::request::Parts;
+use hyper::{body::HttpBody as _, client::HttpConnector, Client};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use hyper_tls::HttpsConnector;
+use serde::Serialize;
+use structopt::StructOpt;
+use tracing::{field, Instrument, Span};
+use tracing_subscriber::EnvFilter;
+
+use wasmtime::*;
+use wasmtime_wasi::{Wasi, WasiCtxBuilder};
+
+mod batch;
+pub mod engine;
+mod gzip;
+mod image_cache;
+mod infer;
+mod metrics;
+mod models;
+mod pool;
+mod predict_dir;
+pub mod tls;
+mod workers;
+
+pub use engine::InferenceEngine;
+
+use image_cache::ImageCache;
+use metrics::Metrics;
+use models::{Labels, ModelInput, ModelRegistry, RegisteredModel};
+use pool::InstancePool;
+use workers::Workers;
+
+const MOBILENET_V2: &str = "./model/mobilenet_v2_1.4_224_frozen.pb";
+const LABELS: &str = "./model/labels.txt";
+const WASM: &str = "./model/optimized-wasi.wasm";
+
+/// Identifier of the next request, in the logs.
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Seconds clients are asked to wait, with the `Retry-After` header, before
+/// retrying the requests sent while the models are loading, which takes a
+/// few seconds.
+const LOADING_RETRY_AFTER: u64 = 5;
+
+/// Path of the readiness endpoint.
+const HEALTHZ_PATH: &str = "/healthz";
+/// Path of the endpoint of the Prometheus metrics.
+const METRICS_PATH: &str = "/metrics";
+/// Path of the endpoint describing the models, see `list_models`.
+const MODELS_PATH: &str = "/models";
+/// Path of the predictions with the default model, which are also served at
+/// `/`, and at `/models/<name>/predict` for the other models.
+const PREDICT_PATH: &str = "/predict";
+
+const ALLOC_FN: &str = "alloc";
+const DEALLOC_FN: &str = "dealloc";
+const MEMORY: &str = "memory";
+const LOAD_MODEL_FN: &str = "load_sized_model_from_ptrs";
+const INFER_FN: &str = "infer_with_handle";
+const INFER_RANDOM_CROP_FN: &str = "infer_random_crop_with_handle";
+const INFER_BATCH_FN: &str = "infer_batch_with_handle";
+const INFER_RAW_FN: &str = "infer_raw_with_handle";
+const INFER_TENSOR_FN: &str = "infer_tensor_with_handle";
+const IMAGE_DIMENSIONS_FN: &str = "image_dimensions";
+const MODEL_STATS_FN: &str = "model_stats";
+const MODEL_CLASSES_FN: &str = "model_classes";
+const MODEL_INPUTS_FN: &str = "model_inputs";
+const SET_INPUT_NAME_FN: &str = "set_input_name";
+const SET_SKIP_BACKGROUND_FN: &str = "set_skip_background";
+const SET_NORMALIZATION_FN: &str = "set_normalization";
+const SET_RESIZE_FILTER_FN: &str = "set_resize_filter";
+const SET_PREPROCESS_FN: &str = "set_preprocess";
+const SET_CLASS_PRIOR_FN: &str = "set_class_prior";
+const SET_OUTPUT_QUANTIZATION_FN: &str = "set_output_quantization";
+const SET_OUTPUT_PROBABILITIES_FN: &str = "set_output_probabilities";
+const TOP_K_FN: &str = "top_k";
+const SET_TIMINGS_FN: &str = "set_timings";
+const SET_MAX_DIMENSION_FN: &str = "set_max_dimension";
+const TIMINGS_FN: &str = "timings";
+
+/// Size of a page of the linear memory of a module.
+const WASM_PAGE_SIZE: usize = 65536;
+
+/// Size of the header written by the raw inference function: the pointer
+/// to the values, the number of dimensions, and up to 8 dimensions.
+const RAW_OUTPUT_HEADER: usize = 40;
+
+/// Header of the prediction responses with the duration of the inference,
+/// in milliseconds, for the image or for the whole batch.
+const INFERENCE_TIME_HEADER: &str = "x-inference-time-ms";
+
+/// Header of the prediction responses with the name of the model.
+const MODEL_HEADER: &str = "x-model";
+
+/// Header of the responses in the raw-f32 format, with the shape of the
+/// output of the model, such as `1,1001`.
+const OUTPUT_SHAPE_HEADER: &str = "x-output-shape";
+
+/// Header with the shape of the tensor sent instead of an image, such as
+/// `1,224,224,3`.
+const INPUT_SHAPE_HEADER: &str = "x-input-shape";
+
+/// Content type of the requests that send a tensor instead of an image.
+const TENSOR_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Image of the warmup inference, a small gray square.
+const WARMUP_IMAGE: &[u8] = include_bytes!("../model/warmup.jpeg");
+
+/// Maximum number of predictions of a response with `?topk`, above which
+/// the value is clamped.
+const MAX_TOP_K: u32 = 100;
+
+/// Default value of `--max-image-bytes`.
+const MAX_IMAGE_BYTES: &str = "5242880";
+
+/// Returned by the inference functions when the requested crop
+/// is larger than the image.
+const CROP_OUT_OF_BOUNDS: i32 = -1;
+
+/// Returned by the inference functions when the model handle is unknown.
+const UNKNOWN_MODEL: i32 = -2;
+
+/// Returned by the inference functions when the image cannot be decoded.
+const DECODE_FAILED: i32 = -3;
+
+/// Returned by the module when the model cannot be loaded or optimized.
+const MODEL_LOAD_FAILED: i32 = -4;
+
+/// Returned by the inference functions when running the model fails.
+const RUN_FAILED: i32 = -5;
+
+/// Returned by the batch inference function when the images are not
+/// packed as expected.
+const INVALID_BATCH: i32 = -7;
+
+/// Returned by the tensor inference function when the tensor does not
+/// match the input of the model.
+const INVALID_TENSOR: i32 = -9;
+
+/// Returned by the inference functions when the image is larger than
+/// `--max-decoded-dimension`.
+const IMAGE_TOO_LARGE: i32 = -11;
+
+/// Command line options for the inference server.
+#[derive(Debug, StructOpt)]
+#[structopt(name = "wasi-tensorflow-inference")]
+pub struct Opts {
+    /// Address and port the server listens on.
+    #[structopt(long, env = "LISTEN_ADDR", default_value = "127.0.0.1:3000")]
+    pub listen: SocketAddr,
+
+    /// Path of the default model, used by requests that do not select one,
+    /// the bundled MobileNet V2 model by default, or an `http(s)://` URL it
+    /// is downloaded from when starting.
+    #[structopt(long, env = "MODEL_PATH", default_value = MOBILENET_V2, global = true)]
+    model: String,
+
+    /// Fail to start if the model downloaded from a URL is larger than this
+    /// number of bytes. The default is 256 MiB.
+    #[structopt(long, default_value = "268435456")]
+    max_model_bytes: usize,
+
+    /// Time in seconds to download the model from a URL, after which the
+    /// server fails to start.
+    #[structopt(long, default_value = "300")]
+    model_fetch_timeout: u64,
+
+    /// Path of the labels of the default model, one per line.
+    #[structopt(long, env = "LABELS_PATH", default_value = LABELS, global = true)]
+    labels: String,
+
+    /// Name of the input of the default model that the images are fed to,
+    /// for models with several inputs.
+    #[structopt(long)]
+    input_name: Option<String>,
+
+    /// JSON file with other models that requests can select by name,
+    /// with their labels (see the readme).
+    #[structopt(long)]
+    models: Option<String>,
+
+    /// Path of the WebAssembly module running the inference, for example
+    /// a build of the module using SIMD instructions.
+    #[structopt(long, env = "WASM_PATH", default_value = WASM, global = true)]
+    wasm: String,
+
+    /// Enable the WebAssembly SIMD proposal when compiling the module.
+    #[structopt(long)]
+    wasm_simd: bool,
+
+    /// Cranelift optimization level used when compiling the module
+    /// (none, speed, or speed-and-size).
+    #[structopt(long, default_value = "speed", parse(try_from_str = parse_opt_level))]
+    wasm_opt_level: OptLevel,
+
+    /// Size in pixels of the square taken from the image when a request
+    /// asks for a random crop.
+    #[structopt(long, default_value = "224")]
+    crop_size: u32,
+
+    /// Labels files for other languages, as a comma separated list of
+    /// `language=path` pairs (for example `en=labels_en.txt,fr=labels_fr.txt`).
+    #[structopt(long, use_delimiter = true, parse(try_from_str = parse_labels_lang))]
+    labels_lang: Vec<(String, String)>,
+
+    /// Language of the labels used when a request does not ask for a
+    /// language that has a labels file.
+    #[structopt(long, default_value = "en")]
+    default_lang: String,
+
+    /// What to do when starting if a labels file does not have one line
+    /// per class of the model (strict, warn, or off). `strict` refuses
+    /// to start, `warn` prints a warning.
+    #[structopt(long, default_value = "warn")]
+    labels_check: LabelsCheck,
+
+    /// How labels are transformed before being returned (raw, lower,
+    /// title, or spaces). `spaces` replaces underscores with spaces.
+    #[structopt(long, default_value = "raw")]
+    label_format: LabelFormat,
+
+    /// How the pixels of the image are normalized for the MobileNet V2
+    /// model (zero-to-one, minus-one-to-one, or mean-std:R,G,B:R,G,B), or
+    /// the preset of a model family (imagenet, mobilenet_tf, or zero_one).
+    #[structopt(long, default_value = "zero-to-one")]
+    normalization: Normalization,
+
+    /// Scale and zero point with which the quantized output of the MobileNet
+    /// V2 model is dequantized, as `SCALE,ZERO_POINT`, such as
+    /// `0.00390625,-128` for an `int8` softmax.
+    #[structopt(long)]
+    output_quantization: Option<OutputQuantization>,
+
+    /// Whether the output of the MobileNet V2 model is probabilities, such
+    /// as the softmax that ends the bundled model, or logits (probabilities
+    /// or logits).
+    #[structopt(long, default_value = "probabilities")]
+    model_output: ModelOutput,
+
+    /// Filter used to resize the images to the input dimensions of the
+    /// model (nearest, triangle, catmull-rom, gaussian, or lanczos3), from
+    /// the fastest to the most accurate.
+    #[structopt(long, default_value = "triangle")]
+    resize_filter: ResizeFilter,
+
+    /// How the images are resized to the input dimensions of the model:
+    /// squash resizes the whole image, center-crop resizes its shorter side
+    /// and keeps its center, as in the ImageNet evaluation of MobileNet.
+    #[structopt(long, default_value = "squash")]
+    preprocess: Preprocess,
+
+    /// What to do when the predicted class has no line in the labels file
+    /// (placeholder or error). `placeholder` responds with a label such as
+    /// `class_742`, `error` fails the request.
+    #[structopt(long, default_value = "error")]
+    labels_fallback: LabelsFallback,
+
+    /// Number of times the inference of a request is retried on a new
+    /// instance when the module traps.
+    #[structopt(long, default_value = "0")]
+    inference_retries: u32,
+
+    /// File with a log-prior for each class of the model, one number per
+    /// line, added to the logits before picking the prediction.
+    #[structopt(long)]
+    class_prior: Option<ClassPrior>,
+
+    /// Number of predictions in each response. With more than one, the
+    /// response has one line per class, followed by its probability.
+    #[structopt(long, default_value = "1")]
+    top_k: u32,
+
+    /// Never predict the first class of the model, which is the
+    /// background class for MobileNet, by masking its score. The server
+    /// does not start with `--no-background`, and the models of `--models`
+    /// are only masked when they set `has_background`.
+    #[structopt(long)]
+    skip_background: bool,
+
+    /// The default model does not have a background class as its first
+    /// class, unlike MobileNet V2.
+    #[structopt(long)]
+    no_background: bool,
+
+    /// Number of classes of the default model before the class of the first
+    /// line of its labels file, such as 1 for a MobileNet labels file that
+    /// does not have a line for the background class.
+    #[structopt(long, default_value = "0")]
+    label_offset: usize,
+
+    /// Reject images whose width or height is smaller than this
+    /// number of pixels.
+    #[structopt(long)]
+    min_dimension: Option<u32>,
+
+    /// Reject images whose width or height is larger than this
+    /// number of pixels.
+    #[structopt(long)]
+    max_dimension: Option<u32>,
+
+    /// Reject images whose width or height, read from their header, is
+    /// larger than this number of pixels, without decoding them, so that
+    /// small files cannot decode into images that exhaust the memory of
+    /// the module.
+    #[structopt(long, default_value = "10000")]
+    max_decoded_dimension: u32,
+
+    /// Maximum number of seconds to wait for the image of a request
+    /// to be downloaded.
+    #[structopt(long, default_value = "10")]
+    fetch_timeout: u64,
+
+    /// Directory from which requests can send `file://` URLs, such as
+    /// `file:///data/images/cat.jpg`, for images on the same host as the
+    /// server. Without it, file URLs are rejected.
+    #[structopt(long, env = "ALLOW_FILE_URLS")]
+    allow_file_urls: Option<PathBuf>,
+
+    /// Number of downloaded images kept by URL, so that requests for the
+    /// same URL do not download it again, or 0 to not keep any.
+    #[structopt(long, env = "IMAGE_CACHE_ENTRIES", default_value = "32")]
+    image_cache_entries: usize,
+
+    /// Maximum size in bytes of all the downloaded images kept.
+    #[structopt(long, default_value = "67108864")]
+    image_cache_bytes: usize,
+
+    /// Reject images larger than this number of bytes, without
+    /// downloading the rest of the image. The default is 5 MiB.
+    #[structopt(long, default_value = MAX_IMAGE_BYTES)]
+    max_image_bytes: usize,
+
+    /// Maximum number of images in a single `multipart/form-data` request.
+    #[structopt(long, default_value = "16")]
+    max_batch_size: usize,
+
+    /// Number of threads running the inferences, the number of CPUs by
+    /// default.
+    #[structopt(long, env = "WORKERS")]
+    workers: Option<usize>,
+
+    /// Number of predictions waiting for a worker, beyond which requests
+    /// get a 503 response. With 0, requests that do not find a free
+    /// worker get it, instead of waiting.
+    #[structopt(long, default_value = "64")]
+    queue_size: usize,
+
+    /// Log the number of nodes and operators of the graph of each model,
+    /// before and after it is optimized, when starting, and add them to
+    /// the response of `/models`.
+    #[structopt(long)]
+    model_stats: bool,
+
+    /// Measure how long the module takes to decode the image, preprocess
+    /// it, and run the model, for each prediction of a single image, and
+    /// add these timings to the logs and the JSON responses.
+    #[structopt(long)]
+    guest_timings: bool,
+
+    /// Do not run the warmup inference when starting, which makes the
+    /// server ready sooner, at the cost of a slower first request.
+    #[structopt(long)]
+    no_warmup: bool,
+
+    /// Only log warnings for each request, unless `RUST_LOG` is set, and
+    /// discard the output of the module. Startup messages are still printed.
+    #[structopt(long, short)]
+    quiet: bool,
+
+    /// Format of the logs, written to stderr: `text`, or `json`, with an
+    /// object per line, such as for a log collector.
+    #[structopt(long, env = "LOG_FORMAT", default_value = "text")]
+    log_format: LogFormat,
+
+    /// Serve HTTP/2 instead of HTTP/1.1. Without TLS, connections are plain
+    /// text, so clients must use HTTP/2 with prior knowledge (h2c).
+    #[structopt(long)]
+    pub http2: bool,
+
+    /// PEM file with the TLS certificate of the server, and its chain, to
+    /// serve HTTPS instead of HTTP, with `--tls-key`.
+    #[structopt(long, env = "TLS_CERT")]
+    tls_cert: Option<String>,
+
+    /// PEM file with the private key of the TLS certificate.
+    #[structopt(long, env = "TLS_KEY")]
+    tls_key: Option<String>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+/// The options of the server started without arguments, without reading
+/// its environment variables, such as for the `InferenceEngine` of other
+/// programs.
+impl Default for Opts {
+    fn default() -> Self {
+        Opts {
+            listen: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            model: MOBILENET_V2.to_string(),
+            max_model_bytes: 256 << 20,
+            model_fetch_timeout: 300,
+            labels: LABELS.to_string(),
+            input_name: None,
+            models: None,
+            wasm: WASM.to_string(),
+            wasm_simd: false,
+            wasm_opt_level: OptLevel::Speed,
+            crop_size: 224,
+            labels_lang: Vec::new(),
+            default_lang: "en".to_string(),
+            labels_check: LabelsCheck::Warn,
+            label_format: LabelFormat::Raw,
+            normalization: Normalization::ZeroToOne,
+            output_quantization: None,
+            model_output: ModelOutput::Probabilities,
+            resize_filter: ResizeFilter::Triangle,
+            preprocess: Preprocess::Squash,
+            labels_fallback: LabelsFallback::Error,
+            inference_retries: 0,
+            class_prior: None,
+            top_k: 1,
+            skip_background: false,
+            no_background: false,
+            label_offset: 0,
+            min_dimension: None,
+            max_dimension: None,
+            max_decoded_dimension: 10000,
+            fetch_timeout: 10,
+            allow_file_urls: None,
+            image_cache_entries: 32,
+            image_cache_bytes: 64 << 20,
+            max_image_bytes: 5 << 20,
+            max_batch_size: 16,
+            workers: None,
+            queue_size: 64,
+            model_stats: false,
+            guest_timings: false,
+            no_warmup: false,
+            quiet: false,
+            log_format: LogFormat::Text,
+            http2: false,
+            tls_cert: None,
+            tls_key: None,
+            command: None,
+        }
+    }
+}
+
+/// How to label a predicted class that has no line in the labels file,
+/// which happens when the labels file does not match the model.
+#[derive(Debug, Clone, Copy)]
+enum LabelsFallback {
+    Placeholder,
+    Error,
+}
+
+impl FromStr for LabelsFallback {
+    type Err = anyhow::Error;
+
+    fn from_str(fallback: &str) -> Result<Self, Self::Err> {
+        match fallback {
+            "placeholder" => Ok(LabelsFallback::Placeholder),
+            "error" => Ok(LabelsFallback::Error),
+            _ => Err(anyhow::Error::msg(format!(
+                "unknown labels fallback {}, expected placeholder or error",
+                fallback
+            ))),
+        }
+    }
+}
+
+/// How the pixels of the image, which range from 0 to 255, are normalized
+/// before running the model. This must match the preprocessing used when
+/// training the model: the models of the TensorFlow slim and Keras
+/// applications, such as MobileNet V1 and V2, use the Inception
+/// preprocessing, in `[-1, 1]`, and models trained with PyTorch usually
+/// use the ImageNet mean and standard deviation.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(tag = "mode", rename_all = "kebab-case")]
+enum Normalization {
+    /// `x / 255`, in `[0, 1]`.
+    ZeroToOne,
+    /// `x / 127.5 - 1`, in `[-1, 1]`.
+    MinusOneToOne,
+    /// `(x / 255 - mean) / std`, for each of the red, green, and blue
+    /// channels.
+    MeanStd { mean: [f32; 3], std: [f32; 3] },
+}
+
+impl Normalization {
+    /// Return the arguments of the module's normalization function.
+    fn args(self) -> Vec<Val> {
+        let (mode, mean, std) = match self {
+            Normalization::ZeroToOne => (0, [0.0; 3], [1.0; 3]),
+            Normalization::MinusOneToOne => (1, [0.0; 3], [1.0; 3]),
+            Normalization::MeanStd { mean, std } => (2, mean, std),
+        };
+        std::iter::once(Val::from(mode))
+            .chain(mean.iter().chain(&std).map(|v| Val::from(*v)))
+            .collect()
+    }
+}
+
+impl FromStr for Normalization {
+    type Err = anyhow::Error;
+
+    fn from_str(normalization: &str) -> Result<Self, Self::Err> {
+        let mut parts = normalization.split(':');
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some("zero-to-one"), None, _, _) => Ok(Normalization::ZeroToOne),
+            (Some("minus-one-to-one"), None, _, _) => Ok(Normalization::MinusOneToOne),
+            (Some("mean-std"), Some(mean), Some(std), None) => {
+                let mean = parse_channels("mean", mean)?;
+                let std = parse_channels("std", std)?;
+                if std.contains(&0.0) {
+                    return Err(anyhow::Error::msg("std values must not be 0"));
+                }
+                Ok(Normalization::MeanStd { mean, std })
+            }
+            _ => normalization
+                .parse::<NormalizationPreset>()
+                .map(NormalizationPreset::normalization)
+                .map_err(|_| {
+                    anyhow::Error::msg(format!(
+                        "unknown normalization {}, expected zero-to-one, minus-one-to-one, mean-std:R,G,B:R,G,B, \
+                         or a preset (imagenet, mobilenet_tf, or zero_one)",
+                        normalization
+                    ))
+                }),
+        }
+    }
+}
+
+/// How the `u8` or `i8` output of a quantized model is dequantized into
+/// probabilities, as `(q - zero_point) * scale`. The frozen graphs do not
+/// have these parameters, which are those of the model when it was
+/// quantized.
+#[derive(Debug, Clone, Copy)]
+struct OutputQuantization {
+    scale: f32,
+    zero_point: i32,
+}
+
+impl FromStr for OutputQuantization {
+    type Err = anyhow::Error;
+
+    fn from_str(quantization: &str) -> Result<Self, Self::Err> {
+        let mut parts = quantization.split(',');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(scale), Some(zero_point), None) => {
+                match (scale.trim().parse::<f32>(), zero_point.trim().parse()) {
+                    (Ok(scale), Ok(zero_point)) if scale.is_finite() && scale > 0.0 => {
+                        Ok(OutputQuantization { scale, zero_point })
+                    }
+                    _ => Err(anyhow::Error::msg(format!(
+                        "invalid output quantization {}, expected a positive scale and an integer zero point",
+                        quantization
+                    ))),
+                }
+            }
+            _ => Err(anyhow::Error::msg(format!(
+                "invalid output quantization {}, expected SCALE,ZERO_POINT",
+                quantization
+            ))),
+        }
+    }
+}
+
+/// What the output of a model is. The module picks the predicted class
+/// from the logits, after adding the class prior, and returns their softmax
+/// as the probabilities of the classes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ModelOutput {
+    /// Scores of any sign, such as the output of the last dense layer.
+    Logits,
+    /// Probabilities, such as the output of a softmax, whose log is used
+    /// as the logits.
+    Probabilities,
+}
+
+impl FromStr for ModelOutput {
+    type Err = anyhow::Error;
+
+    fn from_str(output: &str) -> Result<Self, Self::Err> {
+        match output {
+            "logits" => Ok(ModelOutput::Logits),
+            "probabilities" => Ok(ModelOutput::Probabilities),
+            _ => Err(anyhow::Error::msg(format!(
+                "unknown model output {}, expected logits or probabilities",
+                output
+            ))),
+        }
+    }
+}
+
+/// Mean of the red, green, and blue channels of the ImageNet images, in
+/// `[0, 1]`, with which most models trained with PyTorch normalize them.
+const IMAGENET_MEAN: [f32; 3] = [0.485, 0.456, 0.406];
+/// Standard deviation of the channels of the ImageNet images.
+const IMAGENET_STD: [f32; 3] = [0.229, 0.224, 0.225];
+
+/// Normalization of common model families, which can be selected by name
+/// instead of looking up their constants. The per-channel constants apply
+/// to the 3 channels of the images, which is the only number of channels
+/// of the inputs of the models (see `RegisteredModel::new`).
+#[derive(Debug, Clone, Copy)]
+enum NormalizationPreset {
+    /// The ImageNet mean and standard deviation, for models trained with
+    /// the `torchvision` preprocessing, such as most PyTorch models.
+    ImageNet,
+    /// `[-1, 1]`, for the TensorFlow slim and Keras MobileNet models.
+    MobilenetTf,
+    /// `[0, 1]`, the default normalization.
+    ZeroOne,
+}
+
+impl NormalizationPreset {
+    fn normalization(self) -> Normalization {
+        match self {
+            NormalizationPreset::ImageNet => Normalization::MeanStd {
+                mean: IMAGENET_MEAN,
+                std: IMAGENET_STD,
+            },
+            NormalizationPreset::MobilenetTf => Normalization::MinusOneToOne,
+            NormalizationPreset::ZeroOne => Normalization::ZeroToOne,
+        }
+    }
+}
+
+impl FromStr for NormalizationPreset {
+    type Err = anyhow::Error;
+
+    fn from_str(preset: &str) -> Result<Self, Self::Err> {
+        match preset {
+            "imagenet" => Ok(NormalizationPreset::ImageNet),
+            "mobilenet_tf" => Ok(NormalizationPreset::MobilenetTf),
+            "zero_one" => Ok(NormalizationPreset::ZeroOne),
+            _ => Err(anyhow::Error::msg(format!(
+                "unknown normalization preset {}, expected imagenet, mobilenet_tf, or zero_one",
+                preset
+            ))),
+        }
+    }
+}
+
+/// Filter used by the module to resize the images, see
+/// `image::imageops::FilterType`.
+///
+/// The filter changes the pixels given to the model, so changing it can
+/// slightly change the scores, and even the predicted class, of the same
+/// image; predictions are only reproducible with the same filter.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum ResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+}
+
+impl FromStr for ResizeFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(filter: &str) -> Result<Self, Self::Err> {
+        match filter {
+            "nearest" => Ok(ResizeFilter::Nearest),
+            "triangle" => Ok(ResizeFilter::Triangle),
+            "catmull-rom" => Ok(ResizeFilter::CatmullRom),
+            "gaussian" => Ok(ResizeFilter::Gaussian),
+            "lanczos3" => Ok(ResizeFilter::Lanczos3),
+            _ => Err(anyhow::Error::msg(format!(
+                "unknown resize filter {}, expected nearest, triangle, catmull-rom, gaussian, or lanczos3",
+                filter
+            ))),
+        }
+    }
+}
+
+/// How the module resizes the images to the input dimensions of the model.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Preprocess {
+    /// Resize the whole image, which distorts images that do not have the
+    /// aspect ratio of the model.
+    Squash,
+    /// Resize the image so that its shorter side is 256 pixels, for an
+    /// input of 224 x 224 pixels, and take its center.
+    CenterCrop,
+}
+
+impl FromStr for Preprocess {
+    type Err = anyhow::Error;
+
+    fn from_str(preprocess: &str) -> Result<Self, Self::Err> {
+        match preprocess {
+            "squash" => Ok(Preprocess::Squash),
+            "center-crop" => Ok(Preprocess::CenterCrop),
+            _ => Err(anyhow::Error::msg(format!(
+                "unknown preprocessing {}, expected squash or center-crop",
+                preprocess
+            ))),
+        }
+    }
+}
+
+/// Format of the logs of the server, selected with `--log-format`.
+#[derive(Debug, Clone, Copy)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow::Error::msg(format!(
+                "unknown log format {}, expected text or json",
+                format
+            ))),
+        }
+    }
+}
+
+/// Transformation applied to labels before they are returned.
+#[derive(Debug, Clone, Copy)]
+enum LabelFormat {
+    Raw,
+    Lower,
+    Title,
+    Spaces,
+}
+
+impl LabelFormat {
+    /// Return the label transformed according to this format.
+    fn apply(self, label: &str) -> String {
+        match self {
+            LabelFormat::Raw => label.to_string(),
+            LabelFormat::Lower => label.to_lowercase(),
+            LabelFormat::Title => label
+                .split(' ')
+                .map(|word| {
+                    let mut chars = word.chars();
+                    match chars.next() {
+                        Some(first) => first.to_uppercase().chain(chars).collect(),
+                        None => String::new(),
+                    }
+                })
+                .collect::<Vec<String>>()
+                .join(" "),
+            LabelFormat::Spaces => label.replace('_', " "),
+        }
+    }
+}
+
+impl FromStr for LabelFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "raw" => Ok(LabelFormat::Raw),
+            "lower" => Ok(LabelFormat::Lower),
+            "title" => Ok(LabelFormat::Title),
+            "spaces" => Ok(LabelFormat::Spaces),
+            _ => Err(anyhow::Error::msg(format!(
+                "unknown label format {}, expected raw, lower, title, or spaces",
+                format
+            ))),
+        }
+    }
+}
+
+/// Log-prior of each class of the model, read from the `--class-prior` file.
+#[derive(Debug)]
+struct ClassPrior(Vec<f32>);
+
+impl FromStr for ClassPrior {
+    type Err = anyhow::Error;
+
+    fn from_str(path: &str) -> Result<Self, Self::Err> {
+        let file = std::io::BufReader::new(File::open(path)?);
+        let mut prior = Vec::new();
+        for (value, line) in file.lines().zip(1..) {
+            let value = value?;
+            match value.trim().parse::<f32>() {
+                Ok(v) if v.is_finite() => prior.push(v),
+                _ => {
+                    return Err(anyhow::Error::msg(format!(
+                        "expected a number on line {} of {}, got {}",
+                        line, path, value
+                    )))
+                }
+            }
+        }
+        Ok(ClassPrior(prior))
+    }
+}
+
+/// How to react when a labels file does not match the number of classes of
+/// the model, which is checked when starting.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LabelsCheck {
+    Strict,
+    Warn,
+    Off,
+}
+
+impl FromStr for LabelsCheck {
+    type Err = anyhow::Error;
+
+    fn from_str(check: &str) -> Result<Self, Self::Err> {
+        match check {
+            "strict" => Ok(LabelsCheck::Strict),
+            "warn" => Ok(LabelsCheck::Warn),
+            "off" => Ok(LabelsCheck::Off),
+            _ => Err(anyhow::Error::msg(format!(
+                "unknown labels check {}, expected strict, warn, or off",
+                check
+            ))),
+        }
+    }
+}
+
+/// Commands that can be run instead of starting the server.
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Run the inference on the images in a directory, and write
+    /// the predictions as JSON lines or CSV.
+    PredictDir(predict_dir::PredictDirOpts),
+    /// Run the inference on a single image with the default model, and
+    /// print its label, or its prediction as JSON.
+    Infer(infer::InferOpts),
+}
+
+impl Opts {
+    /// Return the labels file for the first of the given languages that has
+    /// one, falling back to the default language, then to the default
+    /// labels file.
+    fn labels_file(&self, languages: &[String]) -> &str {
+        let find = |lang: &str| {
+            self.labels_lang
+                .iter()
+                .find(|(l, _)| l.eq_ignore_ascii_case(lang))
+                .map(|(_, path)| path.as_str())
+        };
+
+        languages
+            .iter()
+            .find_map(|lang| {
+                // Also try the primary language of a tag such as `fr-CA`.
+                find(lang).or_else(|| find(lang.split('-').next().unwrap_or_default()))
+            })
+            .or_else(|| find(&self.default_lang))
+            .unwrap_or(&self.labels)
+    }
+}
+
+/// State shared by all the requests handled by the server.
+#[derive(Clone)]
+pub struct State {
+    workers: Arc<Workers>,
+    pool: Arc<InstancePool>,
+    image_cache: Arc<ImageCache>,
+    models: Arc<ModelRegistry>,
+    metrics: Arc<Metrics>,
+    /// Client of the image downloads, whose connections are kept and reused
+    /// by the requests for images on the same host.
+    client: Client<HttpsConnector<HttpConnector>>,
+    opts: Arc<Opts>,
+}
+
+/// Parameters of a single prediction, parsed from the query string
+/// and headers of the request.
+#[derive(Debug, Default)]
+struct PredictParams {
+    /// When set, take a random crop of the image, seeded with this value,
+    /// before resizing it to the input size of the model (`?crop=random&seed=N`).
+    random_crop_seed: Option<u64>,
+
+    /// Languages in which the label is requested, in order of preference,
+    /// from the `lang` parameter followed by the `Accept-Language` header.
+    languages: Vec<String>,
+
+    /// Per-channel mean and standard deviation used to normalize the
+    /// pixels, as `(x / 255 - mean) / std` (`?mean=r,g,b&std=r,g,b`),
+    /// instead of the normalization of the model. A missing mean
+    /// defaults to 0, and a missing standard deviation to 1.
+    mean: Option<[f32; 3]>,
+    std: Option<[f32; 3]>,
+
+    /// What the response contains (`?format=label`, `raw-int`, or `raw-f32`).
+    format: ResponseFormat,
+
+    /// Number of predictions in the response (`?topk=N`), instead of
+    /// `--top-k`, clamped to `MAX_TOP_K`.
+    top_k: Option<u32>,
+
+    /// Shape of the input tensor sent instead of an image, from the
+    /// `X-Input-Shape` header of `application/octet-stream` requests.
+    tensor_shape: Option<Vec<usize>>,
+
+    /// Respond with a `PredictionResponse` in JSON instead of the label,
+    /// when the `Accept` header includes `application/json`.
+    json: bool,
+
+    /// Add the probability of every class to the JSON response, which is
+    /// then implied (`?full=true`).
+    full: bool,
+}
+
+impl PredictParams {
+    /// Parse the parameters from the query string and headers of a request.
+    fn from_parts(parts: &Parts) -> Result<PredictParams, ClientError> {
+        let mut crop = None;
+        let mut seed = None;
+        let mut languages = Vec::new();
+        let mut mean = None;
+        let mut std = None;
+        let mut format = ResponseFormat::Label;
+        let mut top_k = None;
+        let mut full = false;
+        // The keys and values are percent-decoded, such as
+        // `?mean=0.485%2C0.456%2C0.406` or `?lang=fr%2DCA`.
+        let query = parts.uri.query().unwrap_or_default();
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            match &*key {
+                "crop" => crop = Some(value),
+                "seed" => {
+                    seed = Some(value.parse::<u64>().map_err(|_| {
+                        ClientError::bad_request(format!(
+                            "seed must be an unsigned integer, got {}",
+                            value
+                        ))
+                    })?)
+                }
+                "lang" => languages.push(value.into_owned()),
+                "format" => {
+                    format = match &*value {
+                        "label" => ResponseFormat::Label,
+                        "raw-int" => ResponseFormat::RawInt,
+                        "raw-f32" => ResponseFormat::RawF32,
+                        _ => {
+                            return Err(ClientError::bad_request(format!(
+                                "unknown format {}, expected label, raw-int, or raw-f32",
+                                value
+                            )))
+                        }
+                    }
+                }
+                "topk" => {
+                    let k = value
+                        .parse::<u32>()
+                        .ok()
+                        .filter(|k| *k > 0)
+                        .ok_or_else(|| {
+                            ClientError::bad_request(format!(
+                                "topk must be a positive integer, got {}",
+                                value
+                            ))
+                        })?;
+                    top_k = Some(k.min(MAX_TOP_K))
+                }
+                "full" => {
+                    full = match &*value {
+                        "true" => true,
+                        "false" => false,
+                        _ => {
+                            return Err(ClientError::bad_request(format!(
+                                "full must be true or false, got {}",
+                                value
+                            )))
+                        }
+                    }
+                }
+                "mean" => mean = Some(parse_channels(&key, &value)?),
+                "std" => {
+                    let channels = parse_channels(&key, &value)?;
+                    if channels.contains(&0.0) {
+                        return Err(ClientError::bad_request("std values must not be 0"));
+                    }
+                    std = Some(channels)
+                }
+                _ => {
+                    return Err(ClientError::bad_request(format!(
+                        "unknown parameter {}",
+                        key
+                    )))
+                }
+            }
+        }
+
+        let random_crop_seed = match crop.as_deref() {
+            None if seed.is_some() => {
+                return Err(ClientError::bad_request("seed requires crop=random"))
+            }
+            None => None,
+            Some("random") => Some(seed.unwrap_or_default()),
+            Some(other) => {
+                return Err(ClientError::bad_request(format!(
+                    "unknown crop mode {}",
+                    other
+                )))
+            }
+        };
+        if random_crop_seed.is_some() && format == ResponseFormat::RawF32 {
+            return Err(ClientError::bad_request(
+                "random crops are not supported with the raw-f32 format",
+            ));
+        }
+        if full && format != ResponseFormat::Label {
+            return Err(ClientError::bad_request(
+                "full is not supported with the raw formats",
+            ));
+        }
+
+        // Only the language tags are used from the `Accept-Language` header,
+        // the quality values are ignored and the order of the header is kept.
+        if let Some(accept) = parts.headers.get(ACCEPT_LANGUAGE) {
+            let accept = accept
+                .to_str()
+                .map_err(|_| ClientError::bad_request("invalid Accept-Language header"))?;
+            languages.extend(
+                accept
+                    .split(',')
+                    .filter_map(|tag| tag.split(';').next())
+                    .map(str::trim)
+                    .filter(|tag| !tag.is_empty() && *tag != "*")
+                    .map(str::to_string),
+            );
+        }
+
+        // Tensors are passed to the model as they are, so the options that
+        // change the image or its normalization do not apply to them.
+        let tensor_shape = match media_type(parts) {
+            Some(media_type) if media_type == TENSOR_CONTENT_TYPE => Some(tensor_shape(parts)?),
+            _ => None,
+        };
+        if tensor_shape.is_some()
+            && (random_crop_seed.is_some()
+                || mean.is_some()
+                || std.is_some()
+                || format == ResponseFormat::RawF32)
+        {
+            return Err(ClientError::bad_request(
+                "random crops, mean, std, and the raw-f32 format are not supported for tensors",
+            ));
+        }
+
+        let json = full
+            || match parts.headers.get(ACCEPT) {
+                Some(accept) => accept
+                    .to_str()
+                    .map_err(|_| ClientError::bad_request("invalid Accept header"))?
+                    .split(',')
+                    .filter_map(|media_type| media_type.split(';').next())
+                    .any(|media_type| media_type.trim().eq_ignore_ascii_case("application/json")),
+                None => false,
+            };
+
+        Ok(PredictParams {
+            random_crop_seed,
+            languages,
+            mean,
+            std,
+            format,
+            top_k,
+            tensor_shape,
+            json,
+            full,
+        })
+    }
+}
+
+/// Return the media type of the body of a request, without its parameters,
+/// in lowercase.
+fn media_type(parts: &Parts) -> Option<String> {
+    let content_type = parts.headers.get(CONTENT_TYPE)?.to_str().ok()?;
+    let media_type = content_type.split(';').next().unwrap_or_default();
+    Some(media_type.trim().to_ascii_lowercase())
+}
+
+/// Parse the `X-Input-Shape` header of a request that sends a tensor.
+fn tensor_shape(parts: &Parts) -> Result<Vec<usize>, ClientError> {
+    let invalid = || {
+        ClientError::bad_request(format!(
+            "tensors need an {} header with their shape, such as 1,224,224,3",
+            INPUT_SHAPE_HEADER
+        ))
+    };
+    let shape = parts
+        .headers
+        .get(INPUT_SHAPE_HEADER)
+        .and_then(|shape| shape.to_str().ok())
+        .ok_or_else(invalid)?;
+    shape
+        .split(',')
+        .map(|dim| dim.trim().parse::<usize>().map_err(|_| invalid()))
+        .collect()
+}
+
+/// What the response to a prediction contains.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum ResponseFormat {
+    /// The label of the predicted class, or a `PredictionResponse` for
+    /// clients that accept JSON.
+    #[default]
+    Label,
+    /// The index of the predicted class, as a 4-byte little-endian integer.
+    RawInt,
+    /// The whole output tensor of the model, as little-endian `f32` values
+    /// in row-major order, with its shape in the `X-Output-Shape` header.
+    RawF32,
+}
+
+/// An error that is reported to the client with its status code and a
+/// description, instead of closing the connection. Most are caused by the
+/// request, with a 4xx status code.
+#[derive(Debug)]
+struct ClientError {
+    status: StatusCode,
+    message: String,
+    /// Seconds after which the request can be retried, in the `Retry-After`
+    /// header of the response.
+    retry_after: Option<u64>,
+}
+
+impl ClientError {
+    /// The request is malformed (400).
+    fn bad_request<S: Into<String>>(message: S) -> ClientError {
+        ClientError {
+            status: StatusCode::BAD_REQUEST,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    /// The request asks for something it is not allowed to (403).
+    fn forbidden<S: Into<String>>(message: S) -> ClientError {
+        ClientError {
+            status: StatusCode::FORBIDDEN,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    /// The request asks for something that does not exist (404).
+    fn not_found<S: Into<String>>(message: S) -> ClientError {
+        ClientError {
+            status: StatusCode::NOT_FOUND,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    /// The path of the request does not accept its method (405).
+    fn method_not_allowed<S: Into<String>>(message: S) -> ClientError {
+        ClientError {
+            status: StatusCode::METHOD_NOT_ALLOWED,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    /// The body of the request is in an encoding the server does not
+    /// support (415).
+    fn unsupported_media_type<S: Into<String>>(message: S) -> ClientError {
+        ClientError {
+            status: StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    /// The request is well formed, but its image cannot be used (422).
+    fn unprocessable<S: Into<String>>(message: S) -> ClientError {
+        ClientError {
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    /// The image of the request could not be downloaded from its URL, as
+    /// its server cannot be reached, or failed (502).
+    fn bad_gateway<S: Into<String>>(message: S) -> ClientError {
+        ClientError {
+            status: StatusCode::BAD_GATEWAY,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    /// The server cannot handle the request yet (503), but should be able
+    /// to in `retry_after` seconds.
+    fn unavailable<S: Into<String>>(message: S, retry_after: u64) -> ClientError {
+        ClientError {
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            message: message.into(),
+            retry_after: Some(retry_after),
+        }
+    }
+
+    /// The module reported that it failed to run the model (500).
+    fn internal<S: Into<String>>(message: S) -> ClientError {
+        ClientError {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+/// Set up the logs of the server, written to stderr, in both formats, so
+/// that the output of the commands on stdout only has their results.
+/// `RUST_LOG` selects what is logged, such as `RUST_LOG=debug` for the
+/// timings of every request, and takes precedence over `--quiet`.
+pub fn init_tracing(opts: &Opts) {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(if opts.quiet { "warn" } else { "info" }));
+    let logs = tracing_subscriber::fmt().with_env_filter(filter);
+    match opts.log_format {
+        LogFormat::Text => logs
+            .with_writer(std::io::stderr)
+            .with_ansi(std::io::stderr().is_terminal())
+            .init(),
+        // The fields of the events are at the top level of their object,
+        // and the fields of the request, such as its identifier, are in
+        // its `span`.
+        LogFormat::Json => logs
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .with_writer(std::io::stderr)
+            .init(),
+    }
+}
+
+/// Run the command of the options instead of starting the server, if there
+/// is one, and return whether there was.
+pub fn run_command(opts: &Arc<Opts>) -> Result<bool, anyhow::Error> {
+    match &opts.command {
+        Some(Command::PredictDir(cmd)) => {
+            let (module, models) = load(opts)?;
+            predict_dir::run(cmd, opts, &module, models.default_model())?;
+            Ok(true)
+        }
+        Some(Command::Infer(cmd)) => {
+            infer::run(cmd, opts.clone())?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Compile the module and load the models, and start the workers, for the
+/// server to handle requests with the returned state.
+pub fn load_state(opts: Arc<Opts>) -> Result<State, anyhow::Error> {
+    let (module, models) = load(&opts)?;
+    let metrics = Arc::new(Metrics::new());
+    let pool = Arc::new(InstancePool::new(module, opts.clone(), metrics.clone()));
+    let models = Arc::new(models);
+
+    let start = Instant::now();
+    let count = opts
+        .workers
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+    let workers = Workers::start(count, opts.queue_size, {
+        let (pool, models, opts) = (pool.clone(), models.clone(), opts.clone());
+        move || {
+            if opts.no_warmup {
+                return Ok(());
+            }
+            warmup(&pool, models.default_model(), &opts)
+        }
+    })?;
+    if !opts.no_warmup {
+        tracing::info!(warmup = ?start.elapsed(), workers = count, "warmup time");
+    }
+
+    Ok(State {
+        workers: Arc::new(workers),
+        pool,
+        image_cache: Arc::new(ImageCache::new(
+            opts.image_cache_entries,
+            opts.image_cache_bytes,
+        )),
+        models,
+        metrics,
+        client: Client::builder().build(HttpsConnector::new()),
+        opts,
+    })
+}
+
+/// Compile the module, and load the models that can be used by requests,
+/// checking that their labels and the class prior match them.
+fn load(opts: &Opts) -> Result<(Module, ModelRegistry), anyhow::Error> {
+    check_files(opts)?;
+    let config = engine_config(opts)?;
+    tracing::info!(
+        opt_level = ?opts.wasm_opt_level,
+        simd = opts.wasm_simd,
+        "wasmtime config"
+    );
+    let engine = Engine::new(&config);
+
+    // The model is only read once, and shared by all the requests.
+    // Each instance still gets its own copy in its linear memory.
+    let model_bytes = Arc::new(if is_model_url(&opts.model) {
+        download_model(&opts.model, opts)?
+    } else {
+        read_file_bytes(opts.model.clone())?
+    });
+
+    // The module is compiled once, and then instantiated as needed.
+    let module = compile_module(&engine, opts)?;
+
+    let models = ModelRegistry::load(
+        opts,
+        model_bytes,
+        |bytes| model_inputs(&module, bytes, opts),
+        |model| load_classes(&module, model, opts),
+        |model| match opts.model_stats {
+            true => model_stats(&module, model, opts).map(Some),
+            false => Ok(None),
+        },
+    )?;
+    for model in models.models() {
+        check_classes(model, opts)?;
+        if let Some(graph) = model.graph {
+            tracing::info!(
+                model = %model.name,
+                nodes = graph.nodes,
+                operators = graph.operators,
+                optimized_nodes = graph.optimized_nodes,
+                optimized_operators = graph.optimized_operators,
+                "model graph"
+            );
+        }
+    }
+    Ok((module, models))
+}
+
+/// Get the number of classes of a model when loading it, for the server and
+/// the `InferenceEngine`.
+///
+/// The classes of every model are needed by the requests for all of them
+/// (`?full=true`), but a model whose classes are not checked still loads
+/// without them, and its predictions get the error of the module.
+fn load_classes(
+    module: &Module,
+    model: &RegisteredModel,
+    opts: &Opts,
+) -> Result<Option<usize>, anyhow::Error> {
+    let checked =
+        opts.labels_check != LabelsCheck::Off || (model.is_default() && opts.class_prior.is_some());
+    match model_classes(module, model, opts) {
+        Ok(classes) => Ok(Some(classes)),
+        Err(err) if !checked => {
+            tracing::warn!(model = %model.name, error = %err, "cannot get the classes");
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Check that the labels files of a model, and the class prior of the
+/// default model, match the classes of the model, loaded by `load_classes`.
+fn check_classes(model: &RegisteredModel, opts: &Opts) -> Result<(), anyhow::Error> {
+    let classes = || model.classes.expect("the classes are computed");
+    if opts.labels_check != LabelsCheck::Off {
+        check_labels(classes(), model.all_labels(), opts)?;
+    }
+
+    if let Some(prior) = opts.class_prior.as_ref().filter(|_| model.is_default()) {
+        if prior.0.len() != classes() {
+            return Err(anyhow::Error::msg(format!(
+                "the class prior has {} values, but the model has {} classes",
+                prior.0.len(),
+                classes()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Prepare an instance of the default model in the pool of the current
+/// thread, such as a worker, by running the model once on a small image in
+/// it, so that the first requests do not pay for creating the instance and
+/// compiling the model.
+fn warmup(pool: &InstancePool, model: &RegisteredModel, opts: &Opts) -> Result<(), anyhow::Error> {
+    let params = PredictParams::default();
+    pool.with_instance(model, |instance, handle| {
+        infer_image(instance, model, handle, WARMUP_IMAGE, opts, &params)
+    })
+    .map_err(|err| anyhow::Error::msg(format!("the warmup inference failed: {}", err)))?;
+    Ok(())
+}
+
+/// Check that the default model, its labels, and the module exist, listing
+/// all the missing ones, such as when the server does not run from the
+/// directory of the repository, and that the `--allow-file-urls` directory
+/// exists.
+pub fn check_files(opts: &Opts) -> Result<(), anyhow::Error> {
+    let missing: Vec<String> = [
+        ("--model", "MODEL_PATH", &opts.model),
+        ("--labels", "LABELS_PATH", &opts.labels),
+        ("--wasm", "WASM_PATH", &opts.wasm),
+    ]
+    .iter()
+    .filter(|(_, _, path)| !is_model_url(path) && !Path::new(path).is_file())
+    .map(|(flag, env, path)| format!("{} (set with {} or {})", path, flag, env))
+    .collect();
+    if !missing.is_empty() {
+        return Err(anyhow::Error::msg(format!(
+            "missing files: {}",
+            missing.join(", ")
+        )));
+    }
+    if let Some(root) = &opts.allow_file_urls {
+        if !root.is_dir() {
+            return Err(anyhow::Error::msg(format!(
+                "{} is not a directory (set with --allow-file-urls or ALLOW_FILE_URLS)",
+                root.display()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// The endpoints of the server.
+#[derive(Clone, Copy, PartialEq)]
+enum Endpoint {
+    Healthz,
+    Metrics,
+    Models,
+    Predict,
+}
+
+impl Endpoint {
+    /// Return the endpoint served at `path`, if any.
+    fn from_path(path: &str) -> Option<Endpoint> {
+        match path {
+            HEALTHZ_PATH => Some(Endpoint::Healthz),
+            METRICS_PATH => Some(Endpoint::Metrics),
+            MODELS_PATH => Some(Endpoint::Models),
+            "/" | PREDICT_PATH => Some(Endpoint::Predict),
+            _ => path
+                .strip_prefix("/models/")
+                .and_then(|path| path.strip_suffix("/predict"))
+                .filter(|name| !name.is_empty() && !name.contains('/'))
+                .map(|_| Endpoint::Predict),
+        }
+    }
+
+    /// The only method the endpoint accepts.
+    fn method(self) -> Method {
+        match self {
+            Endpoint::Healthz | Endpoint::Metrics | Endpoint::Models => Method::GET,
+            Endpoint::Predict => Method::POST,
+        }
+    }
+}
+
+/// Route a request: `GET /healthz` reports whether the server is ready to
+/// serve predictions, `GET /metrics` returns the metrics of the server,
+/// `GET /models` describes the models, and `POST /`, `POST /predict` and
+/// `POST /models/<name>/predict` are predictions. Other paths get a 404, and
+/// other methods a 405, with the method of the path in the `Allow` header.
+/// Only the health check can be served before the models are loaded.
+pub async fn route(
+    req: Request<Body>,
+    state: Arc<OnceLock<State>>,
+) -> Result<Response<Body>, anyhow::Error> {
+    let endpoint = match Endpoint::from_path(req.uri().path()) {
+        Some(endpoint) => endpoint,
+        None => {
+            return Ok(client_error(&ClientError::not_found(format!(
+                "unknown path {}, expected {}, /models/<name>/predict, {}, {} or {}",
+                req.uri().path(),
+                PREDICT_PATH,
+                MODELS_PATH,
+                HEALTHZ_PATH,
+                METRICS_PATH
+            ))))
+        }
+    };
+    if req.method() != endpoint.method() {
+        let mut res = client_error(&ClientError::method_not_allowed(format!(
+            "{} does not accept {} requests, expected {}",
+            req.uri().path(),
+            req.method(),
+            endpoint.method()
+        )));
+        res.headers_mut()
+            .insert(ALLOW, HeaderValue::from_str(endpoint.method().as_str())?);
+        return Ok(res);
+    }
+
+    if endpoint == Endpoint::Healthz {
+        return Ok(healthz(state.get().is_some()));
+    }
+    let state = match state.get() {
+        Some(state) => state,
+        None => {
+            return Ok(client_error(&ClientError::unavailable(
+                "the models are still loading",
+                LOADING_RETRY_AFTER,
+            )))
+        }
+    };
+    if endpoint == Endpoint::Metrics {
+        let mut res = Response::new(Body::from(state.metrics.render()));
+        res.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; version=0.0.4"),
+        );
+        return Ok(res);
+    }
+    if endpoint == Endpoint::Models {
+        return list_models(state);
+    }
+
+    // The timings of the request are recorded as fields of its span, by
+    // the functions that measure them.
+    let span = tracing::info_span!(
+        "request",
+        id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed),
+        method = %req.method(),
+        path = %req.uri().path(),
+        instantiation_ms = field::Empty,
+        compilation_ms = field::Empty,
+        inference_ms = field::Empty,
+        decode_ms = field::Empty,
+        preprocess_ms = field::Empty,
+        run_ms = field::Empty,
+    );
+    let accepts_gzip = gzip::accepts_gzip(req.headers());
+    let res = match predict(req, state.clone()).instrument(span.clone()).await {
+        Ok(res) => gzip::compress(res, accepts_gzip).await,
+        Err(err) => Err(err),
+    };
+    let _entered = span.enter();
+    match &res {
+        Ok(res) if res.status().is_success() => {
+            tracing::info!(status = res.status().as_u16(), "request completed")
+        }
+        Ok(res) => {
+            state.metrics.error();
+            tracing::info!(status = res.status().as_u16(), "request failed");
+        }
+        Err(err) => {
+            state.metrics.error();
+            tracing::warn!(error = %err, "request failed");
+        }
+    }
+    res
+}
+
+/// Respond to a health check, with 200 once the module is compiled and the
+/// models and their labels are loaded, and 503 before that. This does not
+/// run an inference.
+fn healthz(ready: bool) -> Response<Body> {
+    let (status, body) = if ready {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "loading")
+    };
+    let mut res = Response::new(Body::from(body));
+    *res.status_mut() = status;
+    res
+}
+
+/// Description of a model, in the response of `/models`.
+#[derive(Serialize)]
+struct ModelDescription<'a> {
+    name: &'a str,
+    /// Only TensorFlow frozen graphs are supported.
+    format: &'static str,
+    /// Name of the input of the model, for models with several inputs.
+    input: Option<&'a str>,
+    width: u32,
+    height: u32,
+    channels: u32,
+    normalization: Normalization,
+    classes: Option<usize>,
+    /// Size of the graph, with `--model-stats`.
+    graph: Option<GraphStats>,
+    labels: &'a str,
+}
+
+/// Size of the graph of a model, in nodes and distinct operators, before
+/// and after it is optimized by the module.
+#[derive(Debug, Clone, Copy, Serialize)]
+struct GraphStats {
+    nodes: u32,
+    operators: u32,
+    optimized_nodes: u32,
+    optimized_operators: u32,
+}
+
+/// Body of the response of `/models`.
+#[derive(Serialize)]
+struct ModelsResponse<'a> {
+    models: Vec<ModelDescription<'a>>,
+    preprocessing: PreprocessingDescription,
+}
+
+/// Preprocessing of the images, which applies to every model, in the
+/// response of `/models`.
+#[derive(Serialize)]
+struct PreprocessingDescription {
+    preprocess: Preprocess,
+    resize_filter: ResizeFilter,
+    crop_size: u32,
+    min_dimension: Option<u32>,
+    max_dimension: Option<u32>,
+    skip_background: bool,
+}
+
+/// Respond with the models that requests can select, sorted by name, and
+/// the preprocessing of the images, so that clients can list the models
+/// and reproduce their inputs.
+///
+/// The normalization is the one of the model, which requests can replace
+/// with `?mean=r,g,b&std=r,g,b`.
+fn list_models(state: &State) -> Result<Response<Body>, anyhow::Error> {
+    let opts = &state.opts;
+    let models = state
+        .models
+        .models()
+        .map(|model| ModelDescription {
+            name: &model.name,
+            format: "tensorflow",
+            input: model.input.as_deref(),
+            width: model.width,
+            height: model.height,
+            channels: 3,
+            normalization: model.normalization,
+            classes: model.classes,
+            graph: model.graph,
+            labels: model.labels_file(),
+        })
+        .collect();
+    let body = ModelsResponse {
+        models,
+        preprocessing: PreprocessingDescription {
+            preprocess: opts.preprocess,
+            resize_filter: opts.resize_filter,
+            crop_size: opts.crop_size,
+            min_dimension: opts.min_dimension,
+            max_dimension: opts.max_dimension,
+            skip_background: opts.skip_background,
+        },
+    };
+    let mut res = Response::new(Body::from(serde_json::to_vec(&body)?));
+    res.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    Ok(res)
+}
+
+/// Respond to a request containing an image, or the URL of an image, with
+/// the result of running the model selected by the path of the request on
+/// the image.
+async fn predict(req: Request<Body>, state: State) -> Result<Response<Body>, anyhow::Error> {
+    let (parts, body) = req.into_parts();
+    let model = match state.models.select(parts.uri.path()) {
+        Ok(model) => model,
+        Err(err) => return Ok(client_error(&err)),
+    };
+    let mut model_name = model.name.clone();
+    let params = match PredictParams::from_parts(&parts) {
+        Ok(params) => params,
+        Err(err) => return Ok(client_error(&err)),
+    };
+
+    // The images are read here, and the model runs on one of the workers.
+    let prediction = if let Some(boundary) = batch::multipart_boundary(&parts) {
+        match batch::read_images(&parts, body, boundary, &state.opts).await {
+            Ok(form) => {
+                // The model field of the form can select another model
+                // than the path of the request.
+                let selected = state
+                    .models
+                    .select_with_field(parts.uri.path(), form.model.as_deref());
+                let model = match selected {
+                    Ok(model) => model,
+                    Err(err) => return Ok(client_error(&err)),
+                };
+                model_name = model.name.clone();
+                let job_state = state.clone();
+                state
+                    .workers
+                    .run(move || match &form.images[..] {
+                        // A form with a single image, such as the upload of
+                        // a browser form, gets the response of an image
+                        // sent on its own, with all its parameters.
+                        [image] => get_prediction(image, &job_state, &model, &params),
+                        images => batch::get_batch_prediction(images, &job_state, &model, &params),
+                    })
+                    .await
+                    .and_then(|res| res)
+            }
+            Err(err) => Err(err),
+        }
+    } else {
+        match image_bytes(&parts, body, &state).await {
+            Ok(img_bytes) => {
+                let job_state = state.clone();
+                state
+                    .workers
+                    .run(move || get_prediction(&img_bytes, &job_state, &model, &params))
+                    .await
+                    .and_then(|res| res)
+            }
+            Err(err) => Err(err),
+        }
+    };
+    match prediction {
+        Ok((mut res, duration)) => {
+            let headers = res.headers_mut();
+            headers.insert(
+                INFERENCE_TIME_HEADER,
+                HeaderValue::from(duration.as_millis() as u64),
+            );
+            // Names that cannot be a header value, which the models file
+            // allows, are left out.
+            if let Ok(name) = HeaderValue::from_str(&model_name) {
+                headers.insert(MODEL_HEADER, name);
+            }
+            Ok(res)
+        }
+        Err(err) => match err.downcast_ref::<ClientError>() {
+            Some(err) => Ok(client_error(err)),
+            // The trap is logged by the pool, and its details, such as the
+            // backtrace of the module, are not sent to the client.
+            None if err.is::<Trap>() => Ok(client_error(&ClientError::internal(
+                "the module failed while running the inference",
+            ))),
+            None => {
+                tracing::error!(error = %err, "cannot get prediction");
+                Ok(client_error(&ClientError::internal(
+                    "cannot get prediction",
+                )))
+            }
+        },
+    }
+}
+
+/// Get the image of a request. Requests with an image content type, such as
+/// `image/jpeg`, contain the image itself, requests with the
+/// `application/octet-stream` content type contain a tensor, and any other
+/// request contains a single URL pointing to an image, which is downloaded.
+async fn image_bytes(
+    parts: &Parts,
+    mut body: Body,
+    state: &State,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let opts = &state.opts;
+    let data = gzip::read_body(parts, &mut body, opts.max_image_bytes).await?;
+    if data.is_empty() {
+        return Err(ClientError::bad_request(
+            "the request body is empty, expected an image or an image URL",
+        )
+        .into());
+    }
+    let is_binary = media_type(parts).is_some_and(|media_type| {
+        media_type.starts_with("image/") || media_type == TENSOR_CONTENT_TYPE
+    });
+    if is_binary {
+        return Ok(data);
+    }
+
+    // Images sent without an image content type end up here, and are not
+    // valid UTF-8 unless they are a URL.
+    let body = std::str::from_utf8(&data).map_err(|_| {
+        ClientError::bad_request(
+            "the request body must be an image URL, or an image with an image content type such as image/jpeg",
+        )
+    })?;
+    let url = image_url(body)?;
+    if let Some(path) = url.strip_prefix("file://") {
+        return read_file_url(path, opts).await;
+    }
+    // Only complete downloads, which are at most `--max-image-bytes`, are
+    // kept.
+    if let Some(image) = state.image_cache.get(url) {
+        tracing::debug!(url, "image found in the cache");
+        return Ok(image);
+    }
+    let image = fetch_url_to_bytes(&state.client, url, opts).await?;
+    state.image_cache.insert(url, &image);
+    Ok(image)
+}
+
+/// Read the image of a `file://` URL, whose path must be in the
+/// `--allow-file-urls` directory, after resolving any `..` and symbolic
+/// links, so that requests cannot read other files of the host.
+async fn read_file_url(path: &str, opts: &Opts) -> Result<Vec<u8>, anyhow::Error> {
+    let root = opts.allow_file_urls.clone().ok_or_else(|| {
+        ClientError::forbidden("file URLs are not allowed, see --allow-file-urls")
+    })?;
+    // The host of a file URL is empty, or `localhost`.
+    let path = path.strip_prefix("localhost").unwrap_or(path);
+    let path = percent_decode(path)
+        .filter(|path| path.starts_with('/'))
+        .ok_or_else(|| ClientError::bad_request("invalid file URL"))?;
+    let max_bytes = opts.max_image_bytes;
+
+    tokio::task::spawn_blocking(move || {
+        let unreadable = |err: std::io::Error| {
+            ClientError::unprocessable(format!("the image could not be read: {}", err))
+        };
+        let outside = || ClientError::forbidden(format!("file URLs must be in {}", root.display()));
+        // Paths are checked before reading anything, so that requests
+        // cannot find which files exist outside of the directory, then
+        // again once symbolic links are resolved.
+        let path = Path::new(&path);
+        let canonical_root = root.canonicalize()?;
+        if path.components().any(|c| c == Component::ParentDir)
+            || !(path.starts_with(&root) || path.starts_with(&canonical_root))
+        {
+            return Err(outside().into());
+        }
+        let path = path.canonicalize().map_err(unreadable)?;
+        if !path.starts_with(&canonical_root) {
+            return Err(outside().into());
+        }
+        if metadata(&path).map_err(unreadable)?.len() > max_bytes as u64 {
+            return Err(image_too_large(max_bytes).into());
+        }
+        Ok(read_file_bytes(&path).map_err(unreadable)?)
+    })
+    .await?
+}
+
+/// Decode the `%XX` escapes of the path of a URL, or return `None` if they
+/// are not valid, or the path is not valid UTF-8 once decoded.
+fn percent_decode(path: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut chars = path.bytes();
+    while let Some(byte) = chars.next() {
+        if byte != b'%' {
+            bytes.push(byte);
+            continue;
+        }
+        let hex = [chars.next()?, chars.next()?];
+        bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Validate that the body of a request is a single absolute `http`,
+/// `https` or `file` URL, ignoring any surrounding whitespace such as a
+/// trailing newline.
+fn image_url(body: &str) -> Result<&str, ClientError> {
+    let url = body.trim();
+    let invalid = || ClientError::bad_request("expected a single image URL");
+    if url.is_empty() || url.contains(char::is_whitespace) {
+        return Err(invalid());
+    }
+
+    match url.parse::<hyper::Uri>() {
+        Ok(uri) if uri.host().is_some() => match uri.scheme_str() {
+            Some("http") | Some("https") | Some("file") => Ok(url),
+            Some(scheme) => Err(ClientError::bad_request(format!(
+                "unsupported URL scheme {}, expected http, https, or file",
+                scheme
+            ))),
+            None => Err(invalid()),
+        },
+        // File URLs have no host, such as `file:///data/images/cat.jpg`.
+        _ if url.starts_with("file:///") => Ok(url),
+        _ => Err(invalid()),
+    }
+}
+
+/// Parse a comma separated list of one finite value per color channel.
+fn parse_channels(name: &str, value: &str) -> Result<[f32; 3], ClientError> {
+    let invalid = || {
+        ClientError::bad_request(format!(
+            "{} must be three comma separated numbers, got {}",
+            name, value
+        ))
+    };
+    let channels = value
+        .split(',')
+        .map(|v| v.trim().parse::<f32>().ok().filter(|v| v.is_finite()))
+        .collect::<Option<Vec<f32>>>()
+        .ok_or_else(invalid)?;
+    match channels[..] {
+        [r, g, b] => Ok([r, g, b]),
+        _ => Err(invalid()),
+    }
+}
+
+/// Build a response describing what is wrong with the request.
+fn client_error(err: &ClientError) -> Response<Body> {
+    let mut res = Response::new(Body::from(err.to_string()));
+    *res.status_mut() = err.status;
+    if let Some(retry_after) = err.retry_after {
+        res.headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+    }
+    res
+}
+
+/// A predicted class, in the JSON response.
+#[derive(Serialize)]
+struct Prediction {
+    label: String,
+    index: usize,
+    /// Probability of the class, between 0 and 1.
+    score: f32,
+}
+
+/// Body of the response to clients that accept JSON, such as
+/// `{"label": "golden retriever", "index": 209, "score": 0.73, "inference_ms": 530}`.
+#[derive(Serialize)]
+struct PredictionResponse {
+    #[serde(flatten)]
+    prediction: Prediction,
+    inference_ms: u64,
+    /// The other most likely classes with `--top-k` or `?topk`, after the
+    /// predicted class, most likely first.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    top_k: Vec<Prediction>,
+    /// The stages of the inference in the module, with `--guest-timings`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timings: Option<GuestTimings>,
+    /// The probability of every class, with `?full=true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    probabilities: Option<Probabilities>,
+}
+
+/// Probability of every class, by label, most likely first. It is
+/// serialized as an object with its keys in this order, which a
+/// `serde_json::Map` would sort by label.
+struct Probabilities(Vec<(String, f32)>);
+
+impl Serialize for Probabilities {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(
+            self.0
+                .iter()
+                .map(|(label, probability)| (label, probability)),
+        )
+    }
+}
+
+/// Duration of each stage of an inference, measured by the module, in
+/// milliseconds.
+#[derive(Debug, Clone, Copy, Serialize)]
+struct GuestTimings {
+    /// Decoding the image.
+    decode_ms: f64,
+    /// Cropping and resizing the image, and normalizing its pixels.
+    preprocess_ms: f64,
+    /// Running the model.
+    run_ms: f64,
+}
+
+/// Run the model on an image, and respond with the label of the predicted
+/// class, or with its index, returning the duration of the inference with
+/// the response.
+fn get_prediction(
+    img_bytes: &[u8],
+    state: &State,
+    model: &RegisteredModel,
+    params: &PredictParams,
+) -> Result<(Response<Body>, Duration), anyhow::Error> {
+    if let Some(shape) = &params.tensor_shape {
+        check_tensor(img_bytes, shape, model)?;
+    }
+    if params.format == ResponseFormat::RawF32 {
+        let (values, shape, duration) = with_retries(state, model, |instance, handle| {
+            infer_raw(instance, model, handle, img_bytes, &state.opts, params)
+        })?;
+        state.metrics.inference(1, duration);
+        let shape: Vec<String> = shape.iter().map(|dim| dim.to_string()).collect();
+        let mut res = Response::new(Body::from(values));
+        res.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/octet-stream"),
+        );
+        res.headers_mut().insert(
+            OUTPUT_SHAPE_HEADER,
+            HeaderValue::from_str(&shape.join(","))?,
+        );
+        return Ok((res, duration));
+    }
+
+    // The probability of the predicted class is logged, and part of the
+    // JSON response, so it is needed even for a single prediction. The
+    // module clamps the number of classes to the ones of the model.
+    let count = params.top_k.unwrap_or(state.opts.top_k);
+    let k = match params.full {
+        true => model.classes.map_or(MAX_TOP_K, |classes| classes as u32),
+        false => count.max(1),
+    };
+    let (class, duration, top, timings) = with_retries(state, model, |instance, handle| {
+        let (class, duration) =
+            infer_image(instance, model, handle, img_bytes, &state.opts, params)?;
+        let timings = match state.opts.guest_timings {
+            true => guest_timings(instance)?,
+            false => None,
+        };
+        Ok((class, duration, top_k(instance, k)?, timings))
+    })?;
+    state.metrics.inference(1, duration);
+
+    let labels = model.labels(&state.opts, &params.languages);
+    log_prediction(
+        model,
+        class,
+        labels.get(class, &state.opts).ok().as_deref(),
+        top.first()
+            .filter(|(index, _)| *index == class)
+            .map(|(_, score)| *score),
+        img_bytes.len(),
+        duration,
+    );
+
+    // The raw integer is the class index as a little-endian u32,
+    // which is the line number of the label in the labels file.
+    if params.format == ResponseFormat::RawInt {
+        let mut res = Response::new(Body::from((class as u32).to_le_bytes().to_vec()));
+        res.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/octet-stream"),
+        );
+        return Ok((res, duration));
+    }
+
+    if params.json {
+        let probabilities = match params.full {
+            true => Some(all_probabilities(&top, model, labels, &state.opts)?),
+            false => None,
+        };
+        let mut predictions = Vec::new();
+        for &(index, score) in top.iter().take(count.max(1) as usize) {
+            predictions.push(Prediction {
+                label: labels.get(index, &state.opts)?,
+                index,
+                score,
+            });
+        }
+        let best = match predictions.first() {
+            Some(best) if best.index == class => predictions.remove(0),
+            _ => return Err(anyhow::Error::msg("cannot get the score of the prediction")),
+        };
+        let body = PredictionResponse {
+            prediction: best,
+            inference_ms: duration.as_millis() as u64,
+            top_k: if count > 1 { predictions } else { Vec::new() },
+            timings,
+            probabilities,
+        };
+
+        let mut res = Response::new(Body::from(serde_json::to_vec(&body)?));
+        res.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        return Ok((res, duration));
+    }
+
+    if count <= 1 {
+        let label = labels.get(class, &state.opts)?;
+        return Ok((Response::new(Body::from(label)), duration));
+    }
+
+    // With more than one prediction, respond with one line per class,
+    // followed by its probability.
+    let mut lines = Vec::new();
+    for (class, probability) in top.into_iter().take(count as usize) {
+        let label = labels.get(class, &state.opts)?;
+        lines.push(format!("{}: {:.4}", label, probability));
+    }
+    Ok((Response::new(Body::from(lines.join("\n"))), duration))
+}
+
+/// Return the probability of every class, by label, most likely first.
+/// Labels that are the label of several classes, such as `crane` in the
+/// ImageNet labels, are followed by their index after their first class.
+/// The background class is left out when it is never predicted.
+fn all_probabilities(
+    top: &[(usize, f32)],
+    model: &RegisteredModel,
+    labels: &Labels,
+    opts: &Opts,
+) -> Result<Probabilities, anyhow::Error> {
+    let mut seen = HashSet::new();
+    let mut probabilities = Vec::with_capacity(top.len());
+    for &(index, probability) in top {
+        if index == 1 && model.skips_background(opts) {
+            continue;
+        }
+        let mut label = labels.get(index, opts)?;
+        if !seen.insert(label.clone()) {
+            label = format!("{} ({})", label, index);
+        }
+        probabilities.push((label, probability));
+    }
+    Ok(Probabilities(probabilities))
+}
+
+/// Log a prediction, with the model, the predicted class, its label and its
+/// score, when they are known, and the size of the image, in the span of its
+/// request.
+fn log_prediction(
+    model: &RegisteredModel,
+    index: usize,
+    label: Option<&str>,
+    score: Option<f32>,
+    image_bytes: usize,
+    duration: Duration,
+) {
+    tracing::info!(
+        model = %model.name,
+        index,
+        label,
+        score,
+        image_bytes,
+        inference_ms = duration.as_millis() as u64,
+        "prediction"
+    );
+}
+
+/// Check that a tensor sent instead of an image has the shape of the input
+/// of the model, and the length of this shape in `f32` values.
+fn check_tensor(
+    tensor_bytes: &[u8],
+    shape: &[usize],
+    model: &RegisteredModel,
+) -> Result<(), ClientError> {
+    let input_shape = [1, model.height as usize, model.width as usize, 3];
+    if shape != input_shape {
+        return Err(ClientError::bad_request(format!(
+            "the tensor has a shape of {:?}, but the input of model {} is {:?}",
+            shape, model.name, input_shape
+        )));
+    }
+    let len = input_shape.iter().product::<usize>() * 4;
+    if tensor_bytes.len() != len {
+        return Err(ClientError::bad_request(format!(
+            "a tensor of shape {:?} is {} bytes of little-endian f32 values, got {} bytes",
+            shape,
+            len,
+            tensor_bytes.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Run `f` with an instance of the pool in which the model is compiled,
+/// retrying up to `--inference-retries` times when the module traps.
+fn with_retries<T>(
+    state: &State,
+    model: &RegisteredModel,
+    f: impl Fn(&Instance, i32) -> Result<T, anyhow::Error>,
+) -> Result<T, anyhow::Error> {
+    let mut attempt = 0;
+    loop {
+        match state.pool.with_instance(model, &f) {
+            // Only traps are retried, on a fresh instance, since the pool
+            // drops the instance that trapped.
+            Err(err) if err.is::<Trap>() && attempt < state.opts.inference_retries => {
+                attempt += 1;
+                tracing::warn!(
+                    attempt,
+                    retries = state.opts.inference_retries,
+                    trap = err.to_string().lines().next().unwrap_or_default(),
+                    "retrying inference after a trap"
+                );
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Get the `k` most likely classes of the last inference of the instance,
+/// with their probability, most likely first.
+fn top_k(instance: &Instance, k: u32) -> Result<Vec<(usize, f32)>, anyhow::Error> {
+    // The module writes pairs of a u32 class and an f32 probability
+    // in a buffer allocated by the host.
+    let out_ptr = write_guest_memory(&vec![0; k as usize * 8], instance)?;
+    let results = instance
+        .get_func(TOP_K_FN)
+        .expect("expected top-k function not found")
+        .call(&[Val::from(k as i32), Val::from(out_ptr as i32)])?;
+    let len = match results.first() {
+        Some(Val::I32(len)) => *len as usize,
+        _ => return Err(anyhow::Error::msg("cannot get the top-k predictions")),
+    };
+
+    let out = read_guest_memory(instance, out_ptr, len * 8)?;
+    free_guest_memory(out_ptr, k as usize * 8, instance)?;
+    Ok(out
+        .chunks(8)
+        .map(|c| {
+            (
+                u32::from_le_bytes([c[0], c[1], c[2], c[3]]) as usize,
+                f32::from_le_bytes([c[4], c[5], c[6], c[7]]),
+            )
+        })
+        .collect())
+}
+
+/// Get the duration of each stage of the last inference of the instance,
+/// measured by the module, and record them in the span of the request.
+/// Returns `None` if the module does not time the inferences.
+fn guest_timings(instance: &Instance) -> Result<Option<GuestTimings>, anyhow::Error> {
+    // The module writes the durations as three u64 numbers of microseconds
+    // in a buffer allocated by the host.
+    let out_ptr = write_guest_memory(&[0; 24], instance)?;
+    let results = instance
+        .get_func(TIMINGS_FN)
+        .expect("expected timings function not found")
+        .call(&[Val::from(out_ptr as i32)])?;
+    let out = read_guest_memory(instance, out_ptr, 24)?;
+    free_guest_memory(out_ptr, 24, instance)?;
+    match results.first() {
+        Some(Val::I32(3)) => {}
+        Some(Val::I32(0)) => return Ok(None),
+        _ => {
+            return Err(anyhow::Error::msg(
+                "cannot get the timings of the inference",
+            ))
+        }
+    }
+
+    let ms: Vec<f64> = out
+        .chunks(8)
+        .map(|c| {
+            u64::from_le_bytes([c[0], c[1], c[2], c[3], c[4], c[5], c[6], c[7]]) as f64 / 1000.0
+        })
+        .collect();
+    let timings = GuestTimings {
+        decode_ms: ms[0],
+        preprocess_ms: ms[1],
+        run_ms: ms[2],
+    };
+    let span = Span::current();
+    span.record("decode_ms", timings.decode_ms);
+    span.record("preprocess_ms", timings.preprocess_ms);
+    span.record("run_ms", timings.run_ms);
+    tracing::debug!(?timings, "inference timings");
+    Ok(Some(timings))
+}
+
+/// Run the MobileNet V2 model, loaded with `load_model`, on an image in an
+/// existing instance, and return the index of the predicted class, with
+/// the duration of the inference.
+fn infer_image(
+    instance: &Instance,
+    model: &RegisteredModel,
+    handle: i32,
+    img_bytes: &[u8],
+    opts: &Opts,
+    params: &PredictParams,
+) -> Result<(usize, Duration), anyhow::Error> {
+    if params.tensor_shape.is_some() {
+        return infer_tensor(instance, handle, img_bytes);
+    }
+    let start = Instant::now();
+
+    set_normalization(instance, model, params)?;
+
+    // Write the image contents to the module's linear memory,
+    // and get its pointer.
+    let image = GuestBuffer::write(img_bytes, instance)?;
+
+    if needs_dimensions_check(opts) {
+        check_dimensions(instance, image.ptr, image.len, opts)?;
+    }
+
+    // Get the module's "infer_with_handle" function (or its random crop
+    // variant), which is the entrypoint for executing the inference.
+    // If the function is not found, the execution cannot continue.
+    let infer_fn = match params.random_crop_seed {
+        Some(_) => INFER_RANDOM_CROP_FN,
+        None => INFER_FN,
+    };
+    let infer = instance
+        .get_func(infer_fn)
+        .expect("expected inference function not found");
+
+    // Call the inference function with the handle of the model, and the
+    // pointer and length of the image, followed by the crop size and seed
+    // when a random crop was requested.
+    let mut args = vec![
+        Val::from(handle),
+        Val::from(image.ptr as i32),
+        Val::from(image.len as i32),
+    ];
+    if let Some(seed) = params.random_crop_seed {
+        args.push(Val::from(opts.crop_size as i32));
+        args.push(Val::from(seed as i64));
+    }
+    let results = infer.call(&args)?;
+    drop(image);
+    let duration = start.elapsed();
+    Span::current().record("inference_ms", duration.as_millis() as u64);
+    tracing::debug!(?duration, "inference time");
+
+    // The inference function has one return argument, the index of the
+    // predicted class, or a negative value if the inference failed.
+    match results
+        .first()
+        .expect("expected the result of the inference to have one value")
+    {
+        Val::I32(UNKNOWN_MODEL) => Err(unknown_model_handle(model).into()),
+        Val::I32(CROP_OUT_OF_BOUNDS) => Err(ClientError::bad_request(format!(
+            "a random crop of {0}x{0} does not fit in the image",
+            opts.crop_size
+        ))
+        .into()),
+        Val::I32(DECODE_FAILED) => Err(ClientError::bad_request("cannot decode the image").into()),
+        Val::I32(IMAGE_TOO_LARGE) => Err(decoded_image_too_large(opts).into()),
+        Val::I32(MODEL_LOAD_FAILED) => Err(model_load_failed(model).into()),
+        Val::I32(RUN_FAILED) => {
+            Err(ClientError::internal("cannot run the model on the image").into())
+        }
+        Val::I32(val) if *val >= 0 => Ok((*val as usize, duration)),
+        _ => Err(anyhow::Error::msg("cannot get prediction")),
+    }
+}
+
+/// Run the model, loaded with `load_model`, on an input tensor sent by the
+/// client, instead of an image, and return the index of the predicted
+/// class, with the duration of the inference.
+fn infer_tensor(
+    instance: &Instance,
+    handle: i32,
+    tensor_bytes: &[u8],
+) -> Result<(usize, Duration), anyhow::Error> {
+    let start = Instant::now();
+    let tensor = GuestBuffer::write(tensor_bytes, instance)?;
+    let results = instance
+        .get_func(INFER_TENSOR_FN)
+        .expect("expected tensor inference function not found")
+        .call(&[
+            Val::from(handle),
+            Val::from(tensor.ptr as i32),
+            Val::from(tensor.len as i32),
+        ])?;
+    drop(tensor);
+    let duration = start.elapsed();
+    Span::current().record("inference_ms", duration.as_millis() as u64);
+    tracing::debug!(?duration, "inference time");
+
+    match results.first() {
+        Some(Val::I32(INVALID_TENSOR)) => Err(ClientError::bad_request(
+            "the tensor does not match the input of the model, which must be finite f32 values",
+        )
+        .into()),
+        Some(Val::I32(RUN_FAILED)) => {
+            Err(ClientError::internal("cannot run the model on the tensor").into())
+        }
+        Some(Val::I32(val)) if *val >= 0 => Ok((*val as usize, duration)),
+        _ => Err(anyhow::Error::msg("cannot get prediction")),
+    }
+}
+
+/// Run the model, loaded with `load_model`, on an image in an existing
+/// instance, and return its whole output tensor, as little-endian `f32`
+/// bytes, with its shape and the duration of the inference.
+fn infer_raw(
+    instance: &Instance,
+    model: &RegisteredModel,
+    handle: i32,
+    img_bytes: &[u8],
+    opts: &Opts,
+    params: &PredictParams,
+) -> Result<(Vec<u8>, Vec<usize>, Duration), anyhow::Error> {
+    let start = Instant::now();
+
+    set_normalization(instance, model, params)?;
+    let image = GuestBuffer::write(img_bytes, instance)?;
+    if needs_dimensions_check(opts) {
+        check_dimensions(instance, image.ptr, image.len, opts)?;
+    }
+
+    // The module writes a header with the pointer to the values, the number
+    // of dimensions, and the dimensions of the output.
+    let header_buf = GuestBuffer::write(&[0; RAW_OUTPUT_HEADER], instance)?;
+    let results = instance
+        .get_func(INFER_RAW_FN)
+        .expect("expected raw inference function not found")
+        .call(&[
+            Val::from(handle),
+            Val::from(image.ptr as i32),
+            Val::from(image.len as i32),
+            Val::from(header_buf.ptr as i32),
+        ])?;
+    drop(image);
+    let header = read_guest_memory(instance, header_buf.ptr, RAW_OUTPUT_HEADER)?;
+    drop(header_buf);
+    let duration = start.elapsed();
+    Span::current().record("inference_ms", duration.as_millis() as u64);
+    tracing::debug!(?duration, "inference time");
+
+    let len = match results.first() {
+        Some(Val::I32(UNKNOWN_MODEL)) => return Err(unknown_model_handle(model).into()),
+        Some(Val::I32(DECODE_FAILED)) => {
+            return Err(ClientError::bad_request("cannot decode the image").into())
+        }
+        Some(Val::I32(IMAGE_TOO_LARGE)) => return Err(decoded_image_too_large(opts).into()),
+        Some(Val::I32(RUN_FAILED)) => {
+            return Err(ClientError::internal("cannot run the model on the image").into())
+        }
+        Some(Val::I32(len)) if *len >= 0 => *len as usize,
+        _ => return Err(anyhow::Error::msg("cannot get the output of the model")),
+    };
+    let fields: Vec<u32> = header
+        .chunks(4)
+        .map(|field| u32::from_le_bytes([field[0], field[1], field[2], field[3]]))
+        .collect();
+    let (values_ptr, rank) = (fields[0] as isize, fields[1] as usize);
+    let shape = fields[2..2 + rank]
+        .iter()
+        .map(|dim| *dim as usize)
+        .collect();
+
+    // The values were allocated by the module, and belong to the host.
+    let values = read_guest_memory(instance, values_ptr, len * 4)?;
+    free_guest_memory(values_ptr, len * 4, instance)?;
+    Ok((values, shape, duration))
+}
+
+/// Whether images are checked with `check_dimensions` before running the
+/// inference.
+fn needs_dimensions_check(opts: &Opts) -> bool {
+    opts.min_dimension.is_some() || opts.max_dimension.is_some()
+}
+
+/// Set the normalization of the model, or the one of the request, for
+/// the following inferences of the instance. The normalization is always
+/// set, as the instance may have been used for a request with other
+/// parameters.
+fn set_normalization(
+    instance: &Instance,
+    model: &RegisteredModel,
+    params: &PredictParams,
+) -> Result<(), anyhow::Error> {
+    let normalization = match (params.mean, params.std) {
+        (None, None) => model.normalization,
+        (mean, std) => Normalization::MeanStd {
+            mean: mean.unwrap_or([0.0; 3]),
+            std: std.unwrap_or([1.0; 3]),
+        },
+    };
+    instance
+        .get_func(SET_NORMALIZATION_FN)
+        .expect("expected normalization function not found")
+        .call(&normalization.args())?;
+    Ok(())
+}
+
+/// Compile the model in the instance, and return its handle, which can then
+/// be used by any number of inferences in the instance. The settings that
+/// apply to all the inferences of the model, such as the resize filter and
+/// the preprocessing, or the class prior of the default model, are also set.
+fn load_model(
+    instance: &Instance,
+    model: &RegisteredModel,
+    opts: &Opts,
+) -> Result<i32, anyhow::Error> {
+    let start = Instant::now();
+    let model_bytes = &model.model_bytes[..];
+
+    set_input_name(instance, model.input.as_deref())?;
+    let model_bytes_ptr = write_guest_memory(model_bytes, instance)?;
+    let results = instance
+        .get_func(LOAD_MODEL_FN)
+        .expect("expected load model function not found")
+        .call(&[
+            Val::from(model_bytes_ptr as i32),
+            Val::from(model_bytes.len() as i32),
+            Val::from(model.width as i32),
+            Val::from(model.height as i32),
+        ])?;
+    // The module does not need the model bytes once it is compiled.
+    free_guest_memory(model_bytes_ptr, model_bytes.len(), instance)?;
+    let handle = match results.first() {
+        Some(Val::I32(handle)) if *handle < 0 => return Err(model_load_failed(model).into()),
+        Some(Val::I32(handle)) => *handle,
+        _ => return Err(anyhow::Error::msg("model handle must be Val::I32")),
+    };
+
+    // The filter and the preprocessing are the same for all the models, and
+    // the module numbers them in the order of the `ResizeFilter` and
+    // `Preprocess` variants.
+    instance
+        .get_func(SET_RESIZE_FILTER_FN)
+        .expect("expected resize filter function not found")
+        .call(&[Val::from(opts.resize_filter as i32)])?;
+    instance
+        .get_func(SET_PREPROCESS_FN)
+        .expect("expected preprocess function not found")
+        .call(&[Val::from(opts.preprocess as i32)])?;
+    instance
+        .get_func(SET_MAX_DIMENSION_FN)
+        .expect("expected max dimension function not found")
+        .call(&[Val::from(opts.max_decoded_dimension as i32)])?;
+    if opts.guest_timings {
+        instance
+            .get_func(SET_TIMINGS_FN)
+            .expect("expected timings function not found")
+            .call(&[Val::from(1)])?;
+    }
+
+    let probabilities = model.output == ModelOutput::Probabilities;
+    instance
+        .get_func(SET_OUTPUT_PROBABILITIES_FN)
+        .expect("expected output probabilities function not found")
+        .call(&[Val::from(probabilities as i32)])?;
+    if let Some(quantization) = model.output_quantization {
+        instance
+            .get_func(SET_OUTPUT_QUANTIZATION_FN)
+            .expect("expected output quantization function not found")
+            .call(&[
+                Val::from(quantization.scale),
+                Val::from(quantization.zero_point),
+            ])?;
+    }
+
+    // The default model has a background class when the flag is set, which
+    // `RegisteredModel::new` checks, and the models of `--models` when they
+    // set `has_background`.
+    if model.skips_background(opts) {
+        instance
+            .get_func(SET_SKIP_BACKGROUND_FN)
+            .expect("expected skip background function not found")
+            .call(&[Val::from(1)])?;
+    }
+
+    if !model.is_default() {
+        log_compilation_time(model, start.elapsed());
+        return Ok(handle);
+    }
+
+    if let Some(prior) = &opts.class_prior {
+        let prior_bytes: Vec<u8> = prior.0.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let prior_ptr = write_guest_memory(&prior_bytes, instance)?;
+        instance
+            .get_func(SET_CLASS_PRIOR_FN)
+            .expect("expected class prior function not found")
+            .call(&[
+                Val::from(prior_ptr as i32),
+                Val::from(prior_bytes.len() as i32),
+            ])?;
+        free_guest_memory(prior_ptr, prior_bytes.len(), instance)?;
+    }
+
+    log_compilation_time(model, start.elapsed());
+    Ok(handle)
+}
+
+fn log_compilation_time(model: &RegisteredModel, duration: Duration) {
+    Span::current().record("compilation_ms", duration.as_millis() as u64);
+    tracing::debug!(model = %model.name, ?duration, "model compilation time");
+}
+
+/// Check that the dimensions of the image copied in the instance's memory
+/// are within the bounds configured by `--min-dimension` and `--max-dimension`.
+fn check_dimensions(
+    instance: &Instance,
+    img_bytes_ptr: isize,
+    img_len: usize,
+    opts: &Opts,
+) -> Result<(), anyhow::Error> {
+    let dimensions = instance
+        .get_func(IMAGE_DIMENSIONS_FN)
+        .expect("expected image dimensions function not found");
+    let results = dimensions.call(&[Val::from(img_bytes_ptr as i32), Val::from(img_len as i32)])?;
+
+    // The width and height are packed in a single i64, or it is -1 if
+    // the module could not read the header of the image, which is then not
+    // an image the module can decode, such as an HTML page or random bytes.
+    let (width, height) = match results.first() {
+        Some(Val::I64(-1)) => {
+            return Err(ClientError::bad_request("cannot decode the image").into())
+        }
+        Some(Val::I64(val)) => ((*val >> 32) as u32, *val as u32),
+        _ => return Err(anyhow::Error::msg("image dimensions must be Val::I64")),
+    };
+
+    let smallest = width.min(height);
+    let largest = width.max(height);
+    if opts.min_dimension.is_some_and(|min| smallest < min)
+        || opts.max_dimension.is_some_and(|max| largest > max)
+    {
+        return Err(ClientError::unprocessable(format!(
+            "image dimensions {}x{} are out of bounds (min {}, max {})",
+            width,
+            height,
+            opts.min_dimension
+                .map_or("none".to_string(), |d| d.to_string()),
+            opts.max_dimension
+                .map_or("none".to_string(), |d| d.to_string()),
+        ))
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Get the size of the graph of a model, computed by the module.
+fn model_stats(
+    module: &Module,
+    model: &RegisteredModel,
+    opts: &Opts,
+) -> Result<GraphStats, anyhow::Error> {
+    let instance = create_instance(module, opts)?;
+    set_input_name(&instance, model.input.as_deref())?;
+    let model_bytes_ptr = write_guest_memory(&model.model_bytes, &instance)?;
+
+    // The module writes four u32s in a buffer allocated by the host.
+    let out_ptr = write_guest_memory(&[0; 16], &instance)?;
+    let stats = instance
+        .get_func(MODEL_STATS_FN)
+        .expect("expected model stats function not found");
+    let results = stats.call(&[
+        Val::from(model_bytes_ptr as i32),
+        Val::from(model.model_bytes.len() as i32),
+        Val::from(out_ptr as i32),
+    ])?;
+    if let Some(Val::I32(MODEL_LOAD_FAILED)) = results.first() {
+        return Err(anyhow::Error::msg(format!(
+            "cannot load model {}",
+            model.name
+        )));
+    }
+
+    let out = read_guest_memory(&instance, out_ptr, 16)?;
+    let stats: Vec<u32> = out
+        .chunks(4)
+        .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+        .collect();
+    Ok(GraphStats {
+        nodes: stats[0],
+        operators: stats[1],
+        optimized_nodes: stats[2],
+        optimized_operators: stats[3],
+    })
+}
+
+/// Get the number of classes of the model, computed by the module.
+fn model_classes(
+    module: &Module,
+    model: &RegisteredModel,
+    opts: &Opts,
+) -> Result<usize, anyhow::Error> {
+    let instance = create_instance(module, opts)?;
+    set_input_name(&instance, model.input.as_deref())?;
+    let model_bytes_ptr = write_guest_memory(&model.model_bytes, &instance)?;
+    let results = instance
+        .get_func(MODEL_CLASSES_FN)
+        .expect("expected model classes function not found")
+        .call(&[
+            Val::from(model_bytes_ptr as i32),
+            Val::from(model.model_bytes.len() as i32),
+            Val::from(model.width as i32),
+            Val::from(model.height as i32),
+        ])?;
+    match results.first() {
+        Some(Val::I32(classes)) if *classes >= 0 => Ok(*classes as usize),
+        _ => Err(anyhow::Error::msg(format!(
+            "cannot get the number of classes of model {}",
+            model.name
+        ))),
+    }
+}
+
+/// Get the inputs of the graph of a model, described by the module.
+fn model_inputs(
+    module: &Module,
+    model_bytes: &[u8],
+    opts: &Opts,
+) -> Result<Vec<ModelInput>, anyhow::Error> {
+    let instance = create_instance(module, opts)?;
+    let model_bytes_ptr = write_guest_memory(model_bytes, &instance)?;
+    let inputs = instance
+        .get_func(MODEL_INPUTS_FN)
+        .expect("expected model inputs function not found");
+
+    // The description of the inputs of most models is a single short line,
+    // and the module returns the length of longer ones, to read them again
+    // with a buffer large enough.
+    let mut out_len = 1024;
+    loop {
+        let out_ptr = write_guest_memory(&vec![0; out_len], &instance)?;
+        let results = inputs.call(&[
+            Val::from(model_bytes_ptr as i32),
+            Val::from(model_bytes.len() as i32),
+            Val::from(out_ptr as i32),
+            Val::from(out_len as i32),
+        ])?;
+        let len = match results.first() {
+            Some(Val::I32(len)) if *len >= 0 => *len as usize,
+            _ => return Err(anyhow::Error::msg("cannot load the model")),
+        };
+        if len > out_len {
+            free_guest_memory(out_ptr, out_len, &instance)?;
+            out_len = len;
+            continue;
+        }
+        let description = read_guest_memory(&instance, out_ptr, len)?;
+        return ModelInput::parse_all(&String::from_utf8_lossy(&description));
+    }
+}
+
+/// Select the input of the models loaded next in the instance, by its
+/// name, or the only input of the models.
+fn set_input_name(instance: &Instance, name: Option<&str>) -> Result<(), anyhow::Error> {
+    let name = name.unwrap_or_default().as_bytes();
+    let name_ptr = write_guest_memory(name, instance)?;
+    instance
+        .get_func(SET_INPUT_NAME_FN)
+        .expect("expected set input name function not found")
+        .call(&[Val::from(name_ptr as i32), Val::from(name.len() as i32)])?;
+    free_guest_memory(name_ptr, name.len(), instance)?;
+    Ok(())
+}
+
+/// Check that every labels file has one line per class of the model, after
+/// the label offset, as the predicted class is the line number of its label
+/// plus the offset.
+fn check_labels<'a>(
+    classes: usize,
+    all_labels: impl Iterator<Item = &'a Labels>,
+    opts: &Opts,
+) -> Result<(), anyhow::Error> {
+    for labels in all_labels {
+        if labels.classes() == classes {
+            continue;
+        }
+
+        let mut message = format!(
+            "{} has {} labels, but the model has {} classes",
+            labels.file,
+            labels.len(),
+            classes
+        );
+        let offset = labels.mapping.label_offset;
+        if offset > 0 {
+            message.push_str(&format!(", the first {} of which have no label", offset));
+        }
+        // The usual mismatch is a labels file without the background
+        // class, which shifts all the labels by one.
+        if labels.classes() + 1 == classes && labels.mapping.has_background {
+            message.push_str(&format!(
+                ", set the label offset to {} if the file has no line for the background class (see the readme)",
+                offset + 1
+            ));
+        }
+        match opts.labels_check {
+            LabelsCheck::Strict => return Err(anyhow::Error::msg(message)),
+            _ => tracing::warn!("{}", message),
+        }
+    }
+    Ok(())
+}
+
+/// Return whether `--model` is the URL of the model instead of its path.
+fn is_model_url(model: &str) -> bool {
+    model.starts_with("http://") || model.starts_with("https://")
+}
+
+/// Download the model from `url` when starting, with at most
+/// `--max-model-bytes` bytes in `--model-fetch-timeout` seconds, failing
+/// the startup otherwise.
+///
+/// The download runs on its own thread and runtime, since the model is
+/// loaded from synchronous code, whether or not it runs in the runtime of
+/// the server.
+fn download_model(url: &str, opts: &Opts) -> Result<Vec<u8>, anyhow::Error> {
+    let start = Instant::now();
+    let timeout = Duration::from_secs(opts.model_fetch_timeout);
+    let max_bytes = opts.max_model_bytes;
+    let uri = url
+        .parse::<hyper::Uri>()
+        .map_err(|err| anyhow::Error::msg(format!("invalid model URL {}: {}", url, err)))?;
+    let download = async move {
+        let client = Client::builder().build::<_, hyper::Body>(HttpsConnector::new());
+        let mut res = client.get(uri).await?;
+        if !res.status().is_success() {
+            return Err(anyhow::Error::msg(format!(
+                "the server responded {}",
+                res.status()
+            )));
+        }
+        let mut model = Vec::new();
+        while let Some(next) = res.body_mut().data().await {
+            let chunk = next?;
+            if model.len() + chunk.len() > max_bytes {
+                return Err(anyhow::Error::msg(format!(
+                    "the model is larger than {} bytes (see --max-model-bytes)",
+                    max_bytes
+                )));
+            }
+            model.extend_from_slice(&chunk);
+        }
+        Ok(model)
+    };
+    let result = std::thread::spawn(move || {
+        let mut runtime = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()?;
+        runtime.block_on(async {
+            match tokio::time::timeout(timeout, download).await {
+                Ok(result) => result,
+                Err(_) => Err(anyhow::Error::msg(format!("timed out after {:?}", timeout))),
+            }
+        })
+    })
+    .join()
+    .map_err(|_| anyhow::Error::msg("the model download panicked"))?;
+
+    let model = result.map_err(|err| {
+        anyhow::Error::msg(format!("cannot download the model from {}: {}", url, err))
+    })?;
+    tracing::info!(
+        duration = ?start.elapsed(),
+        bytes = model.len(),
+        "model download time"
+    );
+    Ok(model)
+}
+
+/// Return a buffer with the contents of an image from a given URL.
+/// Note that this will download the contents of a random URL,
+/// which will later be copied into the module's linear memory, so the
+/// download is bounded both in time and in size.
+///
+/// The client is shared by all the requests, and the timeout applies to
+/// each download, including the time spent waiting for a connection.
+async fn fetch_url_to_bytes(
+    client: &Client<HttpsConnector<HttpConnector>>,
+    url: &str,
+    opts: &Opts,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let timeout = Duration::from_secs(opts.fetch_timeout);
+    let download = fetch_with_limit(client, url, opts.max_image_bytes);
+    match tokio::time::timeout(timeout, download).await {
+        Ok(result) => result,
+        Err(_) => Err(ClientError::bad_gateway(format!(
+            "the image could not be downloaded in {:?}",
+            timeout
+        ))
+        .into()),
+    }
+}
+
+/// Download the contents of a URL, failing as soon as they are
+/// larger than `max_bytes`.
+async fn fetch_with_limit(
+    client: &Client<HttpsConnector<HttpConnector>>,
+    url: &str,
+    max_bytes: usize,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let uri = url.parse::<hyper::Uri>()?;
+    let download_failed = |err: &dyn std::fmt::Display| {
+        ClientError::bad_gateway(format!("the image could not be downloaded: {}", err))
+    };
+    let mut res = client.get(uri).await.map_err(|err| download_failed(&err))?;
+
+    // Error pages are not images, so there is no point in downloading them.
+    // Server errors are failures of the download, while other statuses,
+    // such as 404, mean that the URL does not point to an image.
+    let status = res.status();
+    if status.is_server_error() {
+        return Err(download_failed(&status).into());
+    }
+    if !status.is_success() {
+        return Err(ClientError::unprocessable(format!(
+            "the image could not be downloaded: {}",
+            status
+        ))
+        .into());
+    }
+
+    let content_length = res
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<usize>().ok());
+    if content_length.is_some_and(|len| len > max_bytes) {
+        return Err(image_too_large(max_bytes).into());
+    }
+    read_with_limit(res.body_mut(), max_bytes)
+        .await
+        .map_err(|err| match err.downcast::<hyper::Error>() {
+            Ok(err) => download_failed(&err).into(),
+            Err(err) => err,
+        })
+}
+
+/// Read a body, failing as soon as it is larger than `max_bytes`.
+async fn read_with_limit(body: &mut Body, max_bytes: usize) -> Result<Vec<u8>, anyhow::Error> {
+    let mut buf: Vec<u8> = Vec::new();
+    while let Some(next) = body.data().await {
+        let chunk = next?;
+        if buf.len() + chunk.len() > max_bytes {
+            return Err(image_too_large(max_bytes).into());
+        }
+        std::io::Write::write(&mut buf, &chunk)?;
+    }
+    Ok(buf)
+}
+
+fn image_too_large(max_bytes: usize) -> ClientError {
+    ClientError::unprocessable(format!("the image is larger than {} bytes", max_bytes))
+}
+
+fn decoded_image_too_large(opts: &Opts) -> ClientError {
+    ClientError::bad_request(format!(
+        "the image is larger than {0}x{0} pixels",
+        opts.max_decoded_dimension
+    ))
+}
+
+/// A model that the module cannot load, such as a model whose input does
+/// not accept the images resized to its dimensions.
+fn model_load_failed(model: &RegisteredModel) -> ClientError {
+    ClientError::internal(format!("cannot load model {}", model.name))
+}
+
+fn unknown_model_handle(model: &RegisteredModel) -> ClientError {
+    ClientError::internal(format!("unknown handle for model {}", model.name))
+}
+
+/// Write a bytes array into the instance's linear memory
+/// and return the offset relative to the module's memory.
+fn write_guest_memory(bytes: &[u8], instance: &Instance) -> Result<isize, anyhow::Error> {
+    // Get the "memory" export of the module.
+    // If the module does not export it, just panic,
+    // since we are not going to be able to copy the model and image.
+    let memory = instance
+        .get_memory(MEMORY)
+        .expect("expected memory not found");
+
+    // The module is not using any bindgen libraries, so it should export
+    // its own alloc function.
+    //
+    // Get the guest's exported alloc function, and call it with the
+    // length of the byte array we are trying to copy.
+    // The result is an offset relative to the module's linear memory, which is
+    // used to copy the bytes into the module's memory.
+    // Then, return the offset.
+
+    let alloc = instance
+        .get_func(ALLOC_FN)
+        .expect("expected alloc function not found");
+    let alloc_result = alloc.call(&[Val::from(bytes.len() as i32)])?;
+
+    let guest_ptr_offset = match alloc_result
+        .first()
+        .expect("expected the result of the allocation to have one value")
+    {
+        Val::I32(val) => *val as isize,
+        _ => return Err(anyhow::Error::msg("guest pointer must be Val::I32")),
+    };
+
+    // The module's allocator grows the memory as needed, so the block
+    // should always fit, but the host does not rely on it: the memory is
+    // grown if the block ends after it, before writing anything.
+    let start = guest_ptr_offset as usize;
+    let end = start
+        .checked_add(bytes.len())
+        .filter(|_| guest_ptr_offset >= 0)
+        .ok_or_else(|| anyhow::Error::msg("guest allocation out of bounds"))?;
+    if end > memory.data_size() {
+        let pages = (end - memory.data_size()).div_ceil(WASM_PAGE_SIZE);
+        memory.grow(pages as u32).map_err(|err| {
+            anyhow::Error::msg(format!(
+                "cannot grow the guest memory for {} bytes: {}",
+                bytes.len(),
+                err
+            ))
+        })?;
+    }
+    assert!(
+        end <= memory.data_size(),
+        "guest memory is too small for the allocated block"
+    );
+
+    // The memory is only borrowed while copying the bytes, and no guest
+    // function can run (and grow it) in the meantime.
+    let data = unsafe { memory.data_unchecked_mut() };
+    data[start..end].copy_from_slice(bytes);
+    Ok(guest_ptr_offset)
+}
+
+/// Free a block of the instance's linear memory written by
+/// `write_guest_memory`, once the module does not use it anymore.
+/// `len` must be the length of the bytes that were written.
+fn free_guest_memory(ptr: isize, len: usize, instance: &Instance) -> Result<(), anyhow::Error> {
+    let dealloc = instance
+        .get_func(DEALLOC_FN)
+        .expect("expected dealloc function not found");
+    dealloc.call(&[Val::from(ptr as i32), Val::from(len as i32)])?;
+    Ok(())
+}
+
+/// A block of the instance's linear memory written by `write_guest_memory`,
+/// which is freed when it is dropped, so that it is not leaked when the
+/// inference returns early, such as for images rejected by
+/// `check_dimensions`.
+struct GuestBuffer<'a> {
+    instance: &'a Instance,
+    ptr: isize,
+    len: usize,
+}
+
+impl<'a> GuestBuffer<'a> {
+    /// Write `bytes` into the instance's linear memory.
+    fn write(bytes: &[u8], instance: &'a Instance) -> Result<Self, anyhow::Error> {
+        let ptr = write_guest_memory(bytes, instance)?;
+        Ok(GuestBuffer {
+            instance,
+            ptr,
+            len: bytes.len(),
+        })
+    }
+}
+
+impl Drop for GuestBuffer<'_> {
+    fn drop(&mut self) {
+        // An instance that trapped cannot free its memory, but it is
+        // dropped by the pool anyway.
+        if let Err(err) = free_guest_memory(self.ptr, self.len, self.instance) {
+            tracing::debug!(error = %err, "cannot free the guest memory");
+        }
+    }
+}
+
+/// Build the configuration used to create the Wasmtime engine.
+///
+/// The settings that affect compilation are pinned explicitly rather than
+/// relying on Wasmtime's defaults, which can change between versions and
+/// alter the performance of the compiled module.
+fn engine_config(opts: &Opts) -> Result<Config, anyhow::Error> {
+    let mut config = Config::new();
+    config
+        .strategy(Strategy::Cranelift)?
+        .cranelift_opt_level(opts.wasm_opt_level.clone())
+        .wasm_simd(opts.wasm_simd)
+        .wasm_bulk_memory(true)
+        .wasm_multi_value(true)
+        .wasm_threads(false);
+    Ok(config)
+}
+
+/// Parse a Cranelift optimization level from its command line name.
+fn parse_opt_level(level: &str) -> Result<OptLevel, anyhow::Error> {
+    match level {
+        "none" => Ok(OptLevel::None),
+        "speed" => Ok(OptLevel::Speed),
+        "speed-and-size" => Ok(OptLevel::SpeedAndSize),
+        _ => Err(anyhow::Error::msg(format!(
+            "unknown optimization level {}, expected none, speed, or speed-and-size",
+            level
+        ))),
+    }
+}
+
+/// Parse a `language=path` pair of the `--labels-lang` option.
+fn parse_labels_lang(pair: &str) -> Result<(String, String), anyhow::Error> {
+    let mut kv = pair.splitn(2, '=');
+    match (kv.next(), kv.next()) {
+        (Some(lang), Some(path)) if !lang.is_empty() && !path.is_empty() => {
+            Ok((lang.to_string(), path.to_string()))
+        }
+        _ => Err(anyhow::Error::msg(format!(
+            "expected a language=path pair, got {}",
+            pair
+        ))),
+    }
+}
+
+/// Read `len` bytes from the instance's linear memory,
+/// starting at the given offset.
+fn read_guest_memory(
+    instance: &Instance,
+    offset: isize,
+    len: usize,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let memory = instance
+        .get_memory(MEMORY)
+        .expect("expected memory not found");
+
+    // The memory is only borrowed while copying it, and no guest function
+    // can run (and grow it) in the meantime.
+    let data = unsafe { memory.data_unchecked() };
+    data.get(offset as usize..offset as usize + len)
+        .map(|bytes| bytes.to_vec())
+        .ok_or_else(|| anyhow::Error::msg("guest memory read out of bounds"))
+}
+
+/// Compile the WebAssembly module passed with `--wasm`.
+fn compile_module(engine: &Engine, opts: &Opts) -> Result<Module, anyhow::Error> {
+    let start = Instant::now();
+    let module = Module::from_file(engine, &opts.wasm)?;
+    tracing::info!(duration = ?start.elapsed(), "module compilation time");
+    Ok(module)
+}
+
+/// Create a Wasmtime::Instance from a compiled module and
+/// link the WASI imports.
+fn create_instance(module: &Module, opts: &Opts) -> Result<Instance, anyhow::Error> {
+    let start = Instant::now();
+    let store = Store::new(module.engine());
+    let mut linker = Linker::new(&store);
+
+    // Anything written by the module, such as panic messages,
+    // is discarded when running quietly.
+    let mut ctx = WasiCtxBuilder::new();
+    ctx.inherit_stdin();
+    if !opts.quiet {
+        ctx.inherit_stdout().inherit_stderr();
+    }
+    let ctx = ctx.build()?;
+
+    let wasi = Wasi::new(&store, ctx);
+    wasi.add_to_linker(&mut linker)?;
+
+    let instance = linker.instantiate(module)?;
+    let duration = start.elapsed();
+    Span::current().record("instantiation_ms", duration.as_millis() as u64);
+    tracing::debug!(?duration, "module instantiation time");
+    Ok(instance)
+}
+
+/// Return the whole contents of a file, such as a model.
+///
+/// The file is read until its end, instead of reading as many bytes as its
+/// size when it is opened, which can be out of date for a file being replaced.
+pub fn read_file_bytes(path: impl AsRef<Path>) -> Result<Vec<u8>, std::io::Error> {
+    let mut file = File::open(path)?;
+    // The size is only a hint, to allocate the buffer once.
+    let len = file.metadata()?.len() as usize;
+    let mut buf = Vec::with_capacity(len);
+    file.read_to_end(&mut buf)?;
+
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, fs};
+
+    use hyper::service::{make_service_fn, service_fn};
+
+    use super::*;
+
+    /// Parse the options of the server from command line arguments.
+    fn opts(args: &[&str]) -> Opts {
+        Opts::from_iter(std::iter::once("wasi-tensorflow-inference").chain(args.iter().copied()))
+    }
+
+    /// Write a file with the given contents in the temporary directory,
+    /// and return its path.
+    fn temp_file(name: &str, contents: &str) -> String {
+        let file = std::env::temp_dir().join(format!(
+            "wasi-tensorflow-inference-{}-{}",
+            std::process::id(),
+            name
+        ));
+        fs::write(&file, contents).unwrap();
+        file.display().to_string()
+    }
+
+    /// Parse the parameters of a request to `uri`, with the given headers.
+    fn params(uri: &str, headers: &[(&str, &str)]) -> Result<PredictParams, ClientError> {
+        let mut req = Request::builder().uri(uri);
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        PredictParams::from_parts(&req.body(()).unwrap().into_parts().0)
+    }
+
+    #[test]
+    fn default_options_are_the_ones_without_arguments() {
+        // Unless the environment variables of the server are set, which
+        // only apply to the options without arguments.
+        assert_eq!(format!("{:?}", Opts::default()), format!("{:?}", opts(&[])));
+    }
+
+    #[test]
+    fn requests_without_parameters_get_the_defaults() {
+        let params = params("/", &[]).unwrap();
+        assert_eq!(params.random_crop_seed, None);
+        assert!(params.languages.is_empty());
+        assert_eq!(params.mean, None);
+        assert_eq!(params.std, None);
+        assert_eq!(params.format, ResponseFormat::Label);
+        assert_eq!(params.top_k, None);
+        assert_eq!(params.tensor_shape, None);
+        assert!(!params.json);
+        assert!(!params.full);
+    }
+
+    #[test]
+    fn parses_each_parameter() {
+        assert_eq!(
+            params("/?crop=random&seed=42", &[])
+                .unwrap()
+                .random_crop_seed,
+            Some(42)
+        );
+        assert_eq!(
+            params("/?crop=random", &[]).unwrap().random_crop_seed,
+            Some(0)
+        );
+        assert_eq!(params("/?lang=fr", &[]).unwrap().languages, ["fr"]);
+        assert_eq!(
+            params("/?format=raw-int", &[]).unwrap().format,
+            ResponseFormat::RawInt
+        );
+        assert_eq!(
+            params("/?format=raw-f32", &[]).unwrap().format,
+            ResponseFormat::RawF32
+        );
+        assert_eq!(params("/?topk=3", &[]).unwrap().top_k, Some(3));
+        assert_eq!(params("/?topk=100000", &[]).unwrap().top_k, Some(MAX_TOP_K));
+        let full = params("/?full=true", &[]).unwrap();
+        assert!(full.full && full.json);
+        let normalized = params("/?mean=0.5,0.5,0.5&std=0.25,0.5,1", &[]).unwrap();
+        assert_eq!(normalized.mean, Some([0.5, 0.5, 0.5]));
+        assert_eq!(normalized.std, Some([0.25, 0.5, 1.0]));
+    }
+
+    #[test]
+    fn parses_the_headers() {
+        let params = params(
+            "/?lang=de",
+            &[
+                ("accept", "text/plain, application/json;q=0.9"),
+                ("accept-language", "fr-CA, en;q=0.8, *;q=0.1"),
+            ],
+        )
+        .unwrap();
+        assert!(params.json);
+        assert_eq!(params.languages, ["de", "fr-CA", "en"]);
+
+        let tensor = self::params(
+            "/",
+            &[
+                ("content-type", TENSOR_CONTENT_TYPE),
+                (INPUT_SHAPE_HEADER, "1, 224, 224, 3"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(tensor.tensor_shape, Some(vec![1, 224, 224, 3]));
+    }
+
+    #[test]
+    fn decodes_the_parameters() {
+        let params = params("/?mean=0.485%2C0.456%2C0.406&lang=fr%2DCA", &[]).unwrap();
+        assert_eq!(params.mean, Some([0.485, 0.456, 0.406]));
+        assert_eq!(params.languages, ["fr-CA"]);
+    }
+
+    #[test]
+    fn repeated_parameters_keep_their_last_value() {
+        assert_eq!(params("/?topk=2&topk=5", &[]).unwrap().top_k, Some(5));
+        // Each language is a preference, so all of them are kept.
+        assert_eq!(
+            params("/?lang=fr&lang=de", &[]).unwrap().languages,
+            ["fr", "de"]
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_parameters() {
+        for (uri, key) in [
+            ("/?topk=2&cachebuster=1", "cachebuster"),
+            ("/?mean=0.485,0.456,0.406&sdt=0.229,0.224,0.225", "sdt"),
+            ("/?Mean=0.5,0.5,0.5", "Mean"),
+        ] {
+            let err = params(uri, &[]).err().unwrap();
+            assert_eq!(err.status, StatusCode::BAD_REQUEST, "{}", uri);
+            assert_eq!(err.to_string(), format!("unknown parameter {}", key));
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_parameters() {
+        for (uri, message) in [
+            ("/?seed=1", "seed requires crop=random"),
+            ("/?crop=center", "unknown crop mode center"),
+            (
+                "/?crop=random&seed=-1",
+                "seed must be an unsigned integer, got -1",
+            ),
+            ("/?topk=0", "topk must be a positive integer, got 0"),
+            (
+                "/?format=json",
+                "unknown format json, expected label, raw-int, or raw-f32",
+            ),
+            ("/?full=yes", "full must be true or false, got yes"),
+            ("/?std=1,0,1", "std values must not be 0"),
+            (
+                "/?crop=random&format=raw-f32",
+                "random crops are not supported with the raw-f32 format",
+            ),
+            (
+                "/?full=true&format=raw-int",
+                "full is not supported with the raw formats",
+            ),
+        ] {
+            let err = params(uri, &[]).err().unwrap();
+            assert_eq!(err.status, StatusCode::BAD_REQUEST, "{}", uri);
+            assert_eq!(err.to_string(), message, "{}", uri);
+        }
+    }
+
+    #[test]
+    fn parses_channels() {
+        assert_eq!(
+            parse_channels("mean", "0.485,0.456,0.406").unwrap(),
+            [0.485, 0.456, 0.406]
+        );
+        assert_eq!(
+            parse_channels("std", " 0.5, 1 ,-2e-1").unwrap(),
+            [0.5, 1.0, -0.2]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_channels() {
+        for value in [
+            "",
+            "0.5",
+            "0.5,0.5",
+            "0.5,0.5,0.5,0.5",
+            "0.5,,0.5",
+            "red,green,blue",
+            "0.5,0.5,0x1",
+            "0.5,0.5,NaN",
+            "0.5,inf,0.5",
+        ] {
+            let err = parse_channels("mean", value).unwrap_err();
+            assert_eq!(err.status, StatusCode::BAD_REQUEST, "{}", value);
+            assert_eq!(
+                err.to_string(),
+                format!("mean must be three comma separated numbers, got {}", value)
+            );
+        }
+    }
+
+    #[test]
+    fn parses_output_quantizations() {
+        let quantization: OutputQuantization = "0.00390625,-128".parse().unwrap();
+        assert_eq!(quantization.scale, 0.00390625);
+        assert_eq!(quantization.zero_point, -128);
+
+        for value in ["0,0", "-0.5,0", "inf,0", "NaN,0", "0.5,1.5", "0.5,red"] {
+            let err = value.parse::<OutputQuantization>().unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "invalid output quantization {}, expected a positive scale and an integer zero point",
+                    value
+                )
+            );
+        }
+        for value in ["0.5", "0.5,0,0"] {
+            let err = value.parse::<OutputQuantization>().unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "invalid output quantization {}, expected SCALE,ZERO_POINT",
+                    value
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn accepts_a_single_image_url() {
+        for url in [
+            "https://example.com/cat.jpg",
+            "http://example.com:8080/cat.jpg?size=large",
+            "file:///data/images/cat.jpg",
+            "file://localhost/data/images/cat.jpg",
+        ] {
+            assert_eq!(image_url(&format!("{}\r\n", url)).unwrap(), url);
+        }
+    }
+
+    #[test]
+    fn rejects_other_urls() {
+        for (body, message) in [
+            (
+                "ftp://example.com/cat.jpg",
+                "unsupported URL scheme ftp, expected http, https, or file",
+            ),
+            (
+                "https://example.com/cat.jpg https://example.com/dog.jpg",
+                "expected a single image URL",
+            ),
+            (
+                "https://example.com/cat.jpg\nhttps://example.com/dog.jpg",
+                "expected a single image URL",
+            ),
+            ("/cat.jpg", "expected a single image URL"),
+            ("example.com/cat.jpg", "expected a single image URL"),
+            ("  \n", "expected a single image URL"),
+        ] {
+            let err = image_url(body).err().unwrap();
+            assert_eq!(err.status, StatusCode::BAD_REQUEST, "{}", body);
+            assert_eq!(err.to_string(), message, "{}", body);
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_file_urls_in_the_allowed_directory() {
+        let dir = std::env::current_dir().unwrap().join("testdata");
+        let opts = opts(&["--allow-file-urls", dir.to_str().unwrap()]);
+        let husky = format!("{}/husky.jpeg", dir.display());
+        assert_eq!(
+            read_file_url(&husky, &opts).await.unwrap(),
+            include_bytes!("../testdata/husky.jpeg")
+        );
+        let encoded = format!("localhost{}/husky%2Ejpeg", dir.display());
+        assert_eq!(
+            read_file_url(&encoded, &opts).await.unwrap(),
+            include_bytes!("../testdata/husky.jpeg")
+        );
+
+        let status = |err: anyhow::Error| err.downcast_ref::<ClientError>().unwrap().status;
+        let outside = format!("{}/../Cargo.toml", dir.display());
+        let err = read_file_url(&outside, &opts).await.unwrap_err();
+        assert_eq!(status(err), StatusCode::FORBIDDEN);
+        let err = read_file_url("/etc/hostname", &opts).await.unwrap_err();
+        assert_eq!(status(err), StatusCode::FORBIDDEN);
+        let missing = format!("{}/missing.jpeg", dir.display());
+        let err = read_file_url(&missing, &opts).await.unwrap_err();
+        assert_eq!(status(err), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn rejects_file_urls_unless_allowed() {
+        let err = read_file_url("/data/images/cat.jpg", &opts(&[]))
+            .await
+            .unwrap_err();
+        let err = err.downcast_ref::<ClientError>().unwrap();
+        assert_eq!(err.status, StatusCode::FORBIDDEN);
+        assert_eq!(
+            err.to_string(),
+            "file URLs are not allowed, see --allow-file-urls"
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_to_download_from_unreachable_hosts() {
+        // Nothing listens on the discard port of the loopback interface.
+        let client = Client::builder().build(HttpsConnector::new());
+        let err = fetch_url_to_bytes(&client, "http://127.0.0.1:9/cat.jpg", &opts(&[]))
+            .await
+            .unwrap_err();
+        let err = err.downcast_ref::<ClientError>().unwrap();
+        assert_eq!(err.status, StatusCode::BAD_GATEWAY);
+        assert!(
+            err.to_string()
+                .starts_with("the image could not be downloaded: error trying to connect"),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_the_failures_of_the_image_servers() {
+        // Responds with the status of the path, such as `/404`, after
+        // waiting for longer than the timeout for `/slow`.
+        let service = make_service_fn(|_| async {
+            Ok::<_, hyper::Error>(service_fn(|req: Request<Body>| async move {
+                let status = match req.uri().path() {
+                    "/slow" => {
+                        tokio::time::delay_for(Duration::from_secs(5)).await;
+                        StatusCode::OK
+                    }
+                    path => StatusCode::from_bytes(&path.as_bytes()[1..]).unwrap(),
+                };
+                let mut res = Response::new(Body::from(status.to_string()));
+                *res.status_mut() = status;
+                Ok::<_, hyper::Error>(res)
+            }))
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let client = Client::builder().build(HttpsConnector::new());
+        let opts = opts(&["--fetch-timeout", "1"]);
+        for (path, status, message) in [
+            (
+                "/503",
+                StatusCode::BAD_GATEWAY,
+                "the image could not be downloaded: 503 Service Unavailable",
+            ),
+            (
+                "/500",
+                StatusCode::BAD_GATEWAY,
+                "the image could not be downloaded: 500 Internal Server Error",
+            ),
+            (
+                "/slow",
+                StatusCode::BAD_GATEWAY,
+                "the image could not be downloaded in 1s",
+            ),
+            (
+                "/404",
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "the image could not be downloaded: 404 Not Found",
+            ),
+        ] {
+            let url = format!("http://{}{}", addr, path);
+            let err = fetch_url_to_bytes(&client, &url, &opts).await.unwrap_err();
+            let err = err.downcast_ref::<ClientError>().unwrap();
+            assert_eq!(err.status, status, "{}", path);
+            assert_eq!(err.to_string(), message, "{}", path);
+        }
+        let url = format!("http://{}/200", addr);
+        assert_eq!(
+            fetch_url_to_bytes(&client, &url, &opts).await.unwrap(),
+            b"200 OK"
+        );
+    }
+
+    #[test]
+    fn writes_blocks_larger_than_the_guest_memory() {
+        let opts = opts(&[]);
+        let engine = Engine::new(&engine_config(&opts).unwrap());
+        let module = compile_module(&engine, &opts).unwrap();
+        let instance = create_instance(&module, &opts).unwrap();
+        let initial_size = instance.get_memory(MEMORY).unwrap().data_size();
+
+        let bytes: Vec<u8> = (0..initial_size + 3 * WASM_PAGE_SIZE)
+            .map(|i| i as u8)
+            .collect();
+        let ptr = write_guest_memory(&bytes, &instance).unwrap();
+        assert!(instance.get_memory(MEMORY).unwrap().data_size() > initial_size);
+        assert_eq!(
+            read_guest_memory(&instance, ptr, bytes.len()).unwrap(),
+            bytes
+        );
+        free_guest_memory(ptr, bytes.len(), &instance).unwrap();
+    }
+
+    #[test]
+    fn retries_the_inference_after_a_trap() {
+        let state = load_state(Arc::new(opts(&[
+            "--inference-retries",
+            "2",
+            "--workers",
+            "1",
+            "--no-warmup",
+        ])))
+        .unwrap();
+        let model = state.models.default_model();
+        let attempts = Cell::new(0);
+        let attempt = || {
+            attempts.set(attempts.get() + 1);
+            attempts.get()
+        };
+
+        // A transient failure is retried once, on a new instance.
+        let result = with_retries(&state, model, |_, _| match attempt() {
+            1 => Err(Trap::new("transient failure").into()),
+            n => Ok(n),
+        });
+        assert_eq!(result.unwrap(), 2);
+
+        // A persistent one fails after all the retries.
+        attempts.set(0);
+        let result = with_retries(&state, model, |_, _| -> Result<(), _> {
+            attempt();
+            Err(Trap::new("persistent failure").into())
+        });
+        assert!(result.unwrap_err().is::<Trap>());
+        assert_eq!(attempts.get(), 3);
+
+        // Client errors are never retried.
+        attempts.set(0);
+        let result = with_retries(&state, model, |_, _| -> Result<(), _> {
+            attempt();
+            Err(ClientError::bad_request("cannot decode the image").into())
+        });
+        assert!(result.unwrap_err().is::<ClientError>());
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[test]
+    fn resolves_the_labels_file_of_the_languages() {
+        let opts = opts(&[
+            "--labels",
+            "labels.txt",
+            "--labels-lang",
+            "en=labels_en.txt,fr=labels_fr.txt,pt-BR=labels_pt_br.txt",
+        ]);
+        let languages = |tags: &[&str]| tags.iter().map(|tag| tag.to_string()).collect::<Vec<_>>();
+        for (tags, expected) in [
+            (&["fr"][..], "labels_fr.txt"),
+            (&["FR"], "labels_fr.txt"),
+            (&["de", "fr", "en"], "labels_fr.txt"),
+            (&["fr-CA"], "labels_fr.txt"),
+            (&["pt-BR"], "labels_pt_br.txt"),
+            // Without a labels file for them, the default language is used.
+            (&["de"], "labels_en.txt"),
+            (&["pt"], "labels_en.txt"),
+            (&[], "labels_en.txt"),
+        ] {
+            assert_eq!(opts.labels_file(&languages(tags)), expected, "{:?}", tags);
+        }
+
+        // Without a labels file for the default language either, the
+        // default labels file is used.
+        let opts = self::opts(&[
+            "--labels",
+            "labels.txt",
+            "--labels-lang",
+            "fr=labels_fr.txt",
+        ]);
+        assert_eq!(opts.labels_file(&languages(&["de"])), "labels.txt");
+        assert_eq!(opts.labels_file(&languages(&["fr"])), "labels_fr.txt");
+    }
+
+    #[test]
+    fn formats_labels() {
+        use LabelFormat::*;
+        for (format, label, expected) in [
+            (Raw, "Eskimo dog, husky", "Eskimo dog, husky"),
+            (Raw, "great_white_shark", "great_white_shark"),
+            (Lower, "Eskimo dog, husky", "eskimo dog, husky"),
+            (Lower, "ÉCLAIR", "éclair"),
+            (Title, "tabby cat", "Tabby Cat"),
+            (Title, "Eskimo dog, husky", "Eskimo Dog, Husky"),
+            (Title, "great_white_shark", "Great_white_shark"),
+            (Title, "jack-o'-lantern  ", "Jack-o'-lantern  "),
+            (Title, "", ""),
+            (Spaces, "great_white_shark", "great white shark"),
+            (Spaces, "tabby cat", "tabby cat"),
+        ] {
+            assert_eq!(format.apply(label), expected, "{:?} {:?}", format, label);
+        }
+    }
+
+    #[test]
+    fn reads_class_priors() {
+        // The prior is in log space, so most of its values are negative.
+        let file = temp_file("prior.txt", "0\n-1.5\n 2.25 \n-1e3\n");
+        let prior: ClassPrior = file.parse().unwrap();
+        fs::remove_file(&file).unwrap();
+        assert_eq!(prior.0, [0.0, -1.5, 2.25, -1000.0]);
+    }
+
+    #[test]
+    fn rejects_invalid_class_priors() {
+        for (contents, line, value) in [
+            ("0\nlikely\n", 2, "likely"),
+            ("0\n\n0\n", 2, ""),
+            ("NaN\n", 1, "NaN"),
+            ("0\n-inf\n", 2, "-inf"),
+        ] {
+            let file = temp_file("invalid-prior.txt", contents);
+            let err = file.parse::<ClassPrior>().unwrap_err();
+            fs::remove_file(&file).unwrap();
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "expected a number on line {} of {}, got {}",
+                    line, file, value
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_class_priors_of_another_length() {
+        let file = temp_file("short-prior.txt", "0\n-1\n");
+        let opts = opts(&["--class-prior", &file]);
+        fs::remove_file(&file).unwrap();
+        let err = load(&opts).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "the class prior has 2 values, but the model has 1001 classes"
+        );
+    }
+
+    #[test]
+    fn fails_to_start_when_the_labels_do_not_match_the_classes() {
+        const WITHOUT_BACKGROUND: &str = "tests/fixtures/labels-without-background.txt";
+        let err = load(&opts(&[
+            "--labels",
+            WITHOUT_BACKGROUND,
+            "--labels-check",
+            "strict",
+        ]))
+        .err()
+        .unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "{} has 1000 labels, but the model has 1001 classes, set the label offset to 1 \
+                 if the file has no line for the background class (see the readme)",
+                WITHOUT_BACKGROUND
+            )
+        );
+
+        // The file matches the classes after the background class.
+        load(&opts(&[
+            "--labels",
+            WITHOUT_BACKGROUND,
+            "--labels-check",
+            "strict",
+            "--label-offset",
+            "1",
+        ]))
+        .unwrap();
+    }
+}
@@ -0,0 +1,140 @@
+//! Operational metrics of the server, such as the number of predictions
+//! and the inference latency, exposed by `GET /metrics` in the Prometheus
+//! text format.
+
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Upper bounds, in seconds, of the buckets of the inference latency.
+const INFERENCE_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Upper bounds, in seconds, of the buckets of the module instantiation
+/// time, which also includes compiling the model in the new instance.
+const INSTANTIATION_BUCKETS: &[f64] = &[0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// Counters shared by all the requests, updated without locking.
+pub struct Metrics {
+    predictions: AtomicU64,
+    errors: AtomicU64,
+    inference: Histogram,
+    instantiation: Histogram,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            predictions: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            inference: Histogram::new(INFERENCE_BUCKETS),
+            instantiation: Histogram::new(INSTANTIATION_BUCKETS),
+        }
+    }
+
+    /// Record an inference of the model on a number of images, which is
+    /// more than one for batches, that took `duration`.
+    pub fn inference(&self, images: usize, duration: Duration) {
+        self.predictions.fetch_add(images as u64, Ordering::Relaxed);
+        self.inference.observe(duration);
+    }
+
+    /// Record a prediction request that failed.
+    pub fn error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record the creation of a new instance of the module by the pool.
+    pub fn instantiation(&self, duration: Duration) {
+        self.instantiation.observe(duration);
+    }
+
+    /// Return all the metrics, in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        counter(
+            &mut out,
+            "inference_predictions_total",
+            "Number of images on which the model was run.",
+            &self.predictions,
+        );
+        counter(
+            &mut out,
+            "inference_errors_total",
+            "Number of prediction requests that failed.",
+            &self.errors,
+        );
+        self.inference.render(
+            &mut out,
+            "inference_duration_seconds",
+            "Time spent running the model, for each inference.",
+        );
+        self.instantiation.render(
+            &mut out,
+            "inference_instantiation_duration_seconds",
+            "Time spent creating a new instance of the module, and compiling the model in it.",
+        );
+        out
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: &AtomicU64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+}
+
+/// Histogram of durations, with fixed buckets.
+struct Histogram {
+    bounds: &'static [f64],
+    /// Number of observations in each bucket, and above the last bound,
+    /// which are only made cumulative when rendering.
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            match self.bounds.get(i) {
+                Some(bound) => {
+                    let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+                }
+                None => {
+                    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, cumulative);
+                }
+            }
+        }
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, self.count.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,712 @@
+//! Models that can be selected by the path of a request, such as
+//! `/models/flowers/predict`, in addition to the bundled MobileNet V2 model.
+
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    fmt, fs,
+    sync::Arc,
+};
+
+use serde::Deserialize;
+
+use crate::{
+    percent_decode, read_file_bytes, ClientError, GraphStats, LabelsFallback, ModelOutput,
+    Normalization, Opts, OutputQuantization,
+};
+
+/// Name of the bundled MobileNet V2 model, which is used by requests
+/// that do not select a model.
+pub const DEFAULT_MODEL: &str = "mobilenet";
+
+/// Input shape of the models whose graph does not specify the dimensions
+/// of their input, as a single image of 224 x 224 RGB pixels, which is the
+/// input of MobileNet V2.
+pub const INPUT_SHAPE: [usize; 4] = [1, 224, 224, 3];
+
+/// Entry of the `--models` configuration file. Unknown keys, such as a
+/// misspelled one, are rejected instead of being ignored.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ModelConfig {
+    model: String,
+    labels: String,
+    /// Name of the input of the model, for models with several inputs.
+    input: Option<String>,
+    /// Shape of the input, for models whose graph does not specify it.
+    input_shape: Option<Vec<usize>>,
+    /// Same as `--normalization`, zero-to-one by default.
+    normalization: Option<String>,
+    /// Same as `--output-quantization`, for models with a quantized output.
+    output_quantization: Option<String>,
+    /// Same as `--model-output`, logits by default.
+    output: Option<String>,
+    /// Same as `--label-offset`, 0 by default.
+    #[serde(default)]
+    label_offset: usize,
+    /// Whether the first class of the model is a background class, such as
+    /// for MobileNet, false by default.
+    #[serde(default)]
+    has_background: bool,
+}
+
+/// Label of the background class, when the labels file does not have one.
+const BACKGROUND_LABEL: &str = "background";
+
+/// How the classes of a model map to the lines of its labels files.
+///
+/// The classes are numbered from 1, in the order of the output of the
+/// model, and the label of a class is on the line whose number is the class
+/// minus `label_offset`. MobileNet V2 has 1001 classes, the first of which
+/// is a background class: its labels file either has a line for it, with an
+/// offset of 0, or starts with the first real class, with an offset of 1.
+#[derive(Debug, Clone, Copy)]
+pub struct LabelMapping {
+    pub label_offset: usize,
+    /// The first class of the model is a background class, which is masked
+    /// by `--skip-background`, and labeled `background` when the labels file
+    /// does not have a line for it.
+    pub has_background: bool,
+}
+
+/// An input of the graph of a model, as described by the module. The
+/// dimensions that the graph does not specify are `None`, and so is the
+/// shape when the graph does not specify the number of dimensions.
+pub struct ModelInput {
+    pub name: String,
+    pub datum_type: String,
+    pub shape: Option<Vec<Option<usize>>>,
+}
+
+impl ModelInput {
+    /// Parse the description written by the `model_inputs` function of the
+    /// module, with a line for each input.
+    pub fn parse_all(description: &str) -> Result<Vec<Self>, anyhow::Error> {
+        description
+            .lines()
+            .map(|line| {
+                let invalid =
+                    || anyhow::Error::msg(format!("invalid description of an input: {}", line));
+                let mut fields = line.split('\t');
+                let (name, datum_type, dims) = match (fields.next(), fields.next(), fields.next()) {
+                    (Some(name), Some(datum_type), Some(dims)) => (name, datum_type, dims),
+                    _ => return Err(invalid()),
+                };
+                let shape = match dims {
+                    "*" => None,
+                    dims => Some(
+                        dims.split(',')
+                            .filter(|dim| !dim.is_empty())
+                            .map(|dim| match dim {
+                                "?" => Ok(None),
+                                dim => dim.parse().map(Some).map_err(|_| invalid()),
+                            })
+                            .collect::<Result<_, _>>()?,
+                    ),
+                };
+                Ok(ModelInput {
+                    name: name.to_string(),
+                    datum_type: datum_type.to_string(),
+                    shape,
+                })
+            })
+            .collect()
+    }
+
+    /// Return the shape of the images fed to this input, as
+    /// `[1, height, width, 3]`, or `None` when the graph does not specify
+    /// the dimensions of the images.
+    fn image_shape(&self) -> Option<Vec<usize>> {
+        match self.shape.as_deref()? {
+            [None | Some(1), Some(height), Some(width), None | Some(3)] => {
+                Some(vec![1, *height, *width, 3])
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ModelInput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let shape = match &self.shape {
+            Some(shape) => {
+                let dims: Vec<String> = shape
+                    .iter()
+                    .map(|dim| dim.map_or("?".to_string(), |dim| dim.to_string()))
+                    .collect();
+                format!("[{}]", dims.join(", "))
+            }
+            None => "unknown shape".to_string(),
+        };
+        write!(f, "{} ({}, {})", self.name, self.datum_type, shape)
+    }
+}
+
+/// The input of a model that the images are fed to, and its shape.
+pub struct SelectedInput {
+    /// Name of the input, when it was configured, otherwise the model has
+    /// a single input.
+    pub name: Option<String>,
+    pub shape: Vec<usize>,
+}
+
+impl SelectedInput {
+    /// Select the input of the model named `model` from the inputs of its
+    /// graph: the input named `name`, or the only input of the graph. Its
+    /// shape is `shape`, or the shape of the input in the graph, or
+    /// `INPUT_SHAPE` when the graph does not specify it.
+    pub fn select(
+        model: &str,
+        inputs: &[ModelInput],
+        name: Option<String>,
+        shape: Option<Vec<usize>>,
+    ) -> Result<Self, anyhow::Error> {
+        let available = || {
+            inputs
+                .iter()
+                .map(|input| input.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let input = match &name {
+            Some(name) => inputs
+                .iter()
+                .find(|input| &input.name == name)
+                .ok_or_else(|| {
+                    anyhow::Error::msg(format!(
+                        "model {} has no input {}, its inputs are: {}",
+                        model,
+                        name,
+                        available()
+                    ))
+                })?,
+            None if inputs.len() == 1 => &inputs[0],
+            None => {
+                return Err(anyhow::Error::msg(format!(
+                    "model {} has {} inputs, select one by its name (see the readme): {}",
+                    model,
+                    inputs.len(),
+                    available()
+                )))
+            }
+        };
+        let shape = shape
+            .or_else(|| input.image_shape())
+            .unwrap_or_else(|| INPUT_SHAPE.to_vec());
+        Ok(SelectedInput { name, shape })
+    }
+}
+
+impl LabelMapping {
+    /// Return the mapping of the default model, which has a background
+    /// class, as MobileNet V2, unless `--no-background` is set.
+    pub fn default_model(opts: &Opts) -> Self {
+        LabelMapping {
+            label_offset: opts.label_offset,
+            has_background: !opts.no_background,
+        }
+    }
+}
+
+/// The labels of a labels file, read once when starting. The label of a
+/// class is on the line of the file whose number is the index of the class,
+/// minus the label offset of the model.
+pub struct Labels {
+    pub file: String,
+    labels: Vec<String>,
+    pub mapping: LabelMapping,
+}
+
+impl Labels {
+    /// Read all the labels of a labels file.
+    fn read(file: &str, mapping: LabelMapping) -> Result<Self, anyhow::Error> {
+        let content = fs::read_to_string(file)
+            .map_err(|err| anyhow::Error::msg(format!("cannot read {}: {}", file, err)))?;
+
+        // Labels files created on Windows or exported by some tools can start
+        // with a UTF-8 byte order mark, and have trailing carriage returns or
+        // spaces, none of which are part of the labels.
+        let labels = content
+            .trim_start_matches('\u{feff}')
+            .lines()
+            .map(|label| label.trim_end().to_string())
+            .collect();
+        Ok(Labels {
+            file: file.to_string(),
+            labels,
+            mapping,
+        })
+    }
+
+    /// Return the number of labels in the file.
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    /// Return the number of classes of a model with these labels, which is
+    /// the number of labels plus the classes before the first line.
+    pub fn classes(&self) -> usize {
+        self.labels.len() + self.mapping.label_offset
+    }
+
+    /// Get the human-readable label of a prediction, formatted with
+    /// `--label-format`. The predicted class is 1-indexed, and its label is
+    /// on the line of the class minus the label offset.
+    pub fn get(&self, num: usize, opts: &Opts) -> Result<String, anyhow::Error> {
+        let line = num.checked_sub(1 + self.mapping.label_offset);
+        match line.and_then(|n| self.labels.get(n)) {
+            Some(label) => Ok(opts.label_format.apply(label)),
+            None if num == 1 && self.mapping.has_background => {
+                Ok(opts.label_format.apply(BACKGROUND_LABEL))
+            }
+            None => match opts.labels_fallback {
+                LabelsFallback::Placeholder => Ok(format!("class_{}", num)),
+                LabelsFallback::Error => Err(anyhow::Error::msg(format!(
+                    "no label for class {} in {}",
+                    num, self.file
+                ))),
+            },
+        }
+    }
+}
+
+/// A model that requests can use, with its labels.
+pub struct RegisteredModel {
+    pub name: String,
+    pub model_bytes: Arc<Vec<u8>>,
+    /// Name of the input of the model, for models with several inputs.
+    pub input: Option<String>,
+    /// Width and height of the input images of the model.
+    pub width: u32,
+    pub height: u32,
+    pub normalization: Normalization,
+    /// How the quantized output of the model is dequantized, if it has
+    /// other parameters than the default ones of the module.
+    pub output_quantization: Option<OutputQuantization>,
+    /// Whether the output of the model is logits or probabilities.
+    pub output: ModelOutput,
+    pub mapping: LabelMapping,
+    /// Number of classes of the model, computed by the module when the
+    /// model is loaded by the server, unless the module cannot load it.
+    pub classes: Option<usize>,
+    /// Size of the graph of the model, computed by the module when the
+    /// model is loaded by the server with `--model-stats`.
+    pub graph: Option<GraphStats>,
+    labels_file: String,
+    /// The labels of every labels file of the model, by path.
+    labels: BTreeMap<String, Labels>,
+}
+
+impl RegisteredModel {
+    /// Register a model, reading all its labels files. The default model
+    /// also has a labels file for each of the `--labels-lang` languages.
+    pub fn new(
+        name: String,
+        model_bytes: Arc<Vec<u8>>,
+        input: SelectedInput,
+        normalization: Normalization,
+        labels_file: String,
+        mapping: LabelMapping,
+        opts: &Opts,
+    ) -> Result<Self, anyhow::Error> {
+        // The input of the model is a single RGB image, with the
+        // (1, height, width, channels) layout.
+        let (height, width) = match input.shape[..] {
+            [1, height, width, 3] if height > 0 && width > 0 => (height as u32, width as u32),
+            _ => {
+                return Err(anyhow::Error::msg(format!(
+                "model {} has an input shape of {:?}, but only [1, height, width, 3] is supported",
+                name, input.shape
+            )))
+            }
+        };
+        let mut model = RegisteredModel {
+            name,
+            model_bytes,
+            input: input.name,
+            width,
+            height,
+            normalization,
+            output_quantization: None,
+            output: ModelOutput::Logits,
+            mapping,
+            classes: None,
+            graph: None,
+            labels_file,
+            labels: BTreeMap::new(),
+        };
+        let mut files = vec![model.labels_file.clone()];
+        if model.is_default() {
+            if opts.skip_background && !mapping.has_background {
+                return Err(anyhow::Error::msg(
+                    "--skip-background masks the background class, but the default model has none (--no-background)",
+                ));
+            }
+            files.extend(opts.labels_lang.iter().map(|(_, f)| f.clone()));
+            model.output_quantization = opts.output_quantization;
+            model.output = opts.model_output;
+        }
+        for file in files {
+            if let Entry::Vacant(entry) = model.labels.entry(file) {
+                let labels = Labels::read(entry.key(), mapping)?;
+                entry.insert(labels);
+            }
+        }
+        Ok(model)
+    }
+
+    /// Whether this is the bundled MobileNet V2 model, to which the
+    /// MobileNet specific settings, such as the class prior, apply.
+    pub fn is_default(&self) -> bool {
+        self.name == DEFAULT_MODEL
+    }
+
+    /// Whether the first class of the model, its background class, is never
+    /// predicted, with `--skip-background`.
+    pub fn skips_background(&self, opts: &Opts) -> bool {
+        opts.skip_background && self.mapping.has_background
+    }
+
+    /// Return the labels of the model. The labels of the default model
+    /// can be in other languages, see `Opts::labels_file`.
+    pub fn labels(&self, opts: &Opts, languages: &[String]) -> &Labels {
+        let file = if self.is_default() {
+            opts.labels_file(languages)
+        } else {
+            &self.labels_file
+        };
+        &self.labels[file]
+    }
+
+    /// Return the labels of every labels file of the model.
+    pub fn all_labels(&self) -> impl Iterator<Item = &Labels> {
+        self.labels.values()
+    }
+
+    /// Return the path of the labels file of the model, in the default
+    /// language for the default model.
+    pub fn labels_file(&self) -> &str {
+        &self.labels_file
+    }
+}
+
+/// All the models that can be used by requests, loaded when starting.
+pub struct ModelRegistry {
+    models: BTreeMap<String, Arc<RegisteredModel>>,
+}
+
+impl ModelRegistry {
+    /// Load the default model, and the models of the `--models`
+    /// configuration file, if any.
+    ///
+    /// The configuration is a JSON object, mapping the name of each model
+    /// to the paths of its model and labels files, and optionally the name
+    /// and the shape of its input, and the normalization of its pixels (see
+    /// `Normalization`):
+    ///
+    /// `{"flowers": {"model": "flowers.pb", "labels": "flowers.txt", "input_shape": [1, 224, 224, 3]}}`
+    ///
+    /// The inputs of each model are described by `inputs`, from its bytes,
+    /// its number of classes is computed by `classes`, if it can be, and the
+    /// size of its graph by `graph`, if needed.
+    pub fn load(
+        opts: &Opts,
+        default_model_bytes: Arc<Vec<u8>>,
+        inputs: impl Fn(&[u8]) -> Result<Vec<ModelInput>, anyhow::Error>,
+        classes: impl Fn(&RegisteredModel) -> Result<Option<usize>, anyhow::Error>,
+        graph: impl Fn(&RegisteredModel) -> Result<Option<GraphStats>, anyhow::Error>,
+    ) -> Result<Self, anyhow::Error> {
+        let describe = |mut model: RegisteredModel| -> Result<_, anyhow::Error> {
+            model.classes = classes(&model)?;
+            model.graph = graph(&model)?;
+            Ok(Arc::new(model))
+        };
+        let mut models = BTreeMap::new();
+        let input = SelectedInput::select(
+            DEFAULT_MODEL,
+            &inputs(&default_model_bytes)?,
+            opts.input_name.clone(),
+            None,
+        )?;
+        models.insert(
+            DEFAULT_MODEL.to_string(),
+            describe(RegisteredModel::new(
+                DEFAULT_MODEL.to_string(),
+                default_model_bytes,
+                input,
+                opts.normalization,
+                opts.labels.clone(),
+                LabelMapping::default_model(opts),
+                opts,
+            )?)?,
+        );
+
+        let path = match &opts.models {
+            Some(path) => path,
+            None => return Ok(ModelRegistry { models }),
+        };
+        let config: BTreeMap<String, ModelConfig> = serde_json::from_slice(&fs::read(path)?)
+            .map_err(|err| anyhow::Error::msg(format!("invalid models file {}: {}", path, err)))?;
+        for (name, config) in config {
+            if models.contains_key(&name) {
+                return Err(anyhow::Error::msg(format!(
+                    "model {} is already defined",
+                    name
+                )));
+            }
+            let normalization = match &config.normalization {
+                Some(normalization) => normalization
+                    .parse()
+                    .map_err(|err| anyhow::Error::msg(format!("model {}: {}", name, err)))?,
+                None => Normalization::ZeroToOne,
+            };
+            let output_quantization = match &config.output_quantization {
+                Some(quantization) => Some(
+                    quantization
+                        .parse()
+                        .map_err(|err| anyhow::Error::msg(format!("model {}: {}", name, err)))?,
+                ),
+                None => None,
+            };
+            let output = match &config.output {
+                Some(output) => output
+                    .parse()
+                    .map_err(|err| anyhow::Error::msg(format!("model {}: {}", name, err)))?,
+                None => ModelOutput::Logits,
+            };
+
+            let model_bytes = read_file_bytes(config.model.clone()).map_err(|err| {
+                anyhow::Error::msg(format!("cannot read {}: {}", config.model, err))
+            })?;
+            let input = SelectedInput::select(
+                &name,
+                &inputs(&model_bytes)?,
+                config.input,
+                config.input_shape,
+            )?;
+            let mut model = RegisteredModel::new(
+                name.clone(),
+                Arc::new(model_bytes),
+                input,
+                normalization,
+                config.labels,
+                LabelMapping {
+                    label_offset: config.label_offset,
+                    has_background: config.has_background,
+                },
+                opts,
+            )?;
+            model.output_quantization = output_quantization;
+            model.output = output;
+            models.insert(name, describe(model)?);
+        }
+        Ok(ModelRegistry { models })
+    }
+
+    /// Return the default model.
+    pub fn default_model(&self) -> &RegisteredModel {
+        &self.models[DEFAULT_MODEL]
+    }
+
+    /// Return all the models, sorted by name.
+    pub fn models(&self) -> impl Iterator<Item = &RegisteredModel> {
+        self.models.values().map(|model| &**model)
+    }
+
+    /// Select the model from the path of a request: `/models/<name>/predict`
+    /// selects a model by name, and any other path the default model.
+    pub fn select(&self, path: &str) -> Result<Arc<RegisteredModel>, ClientError> {
+        self.select_with_field(path, None)
+    }
+
+    /// Same as `select`, for requests that can also name the model in their
+    /// body, such as with the `model` field of a form, which selects the
+    /// model of requests to any other path than `/models/<name>/predict`.
+    /// Requests to such a path can only name the same model.
+    pub fn select_with_field(
+        &self,
+        path: &str,
+        field: Option<&str>,
+    ) -> Result<Arc<RegisteredModel>, ClientError> {
+        let mut segments = path.trim_matches('/').split('/');
+        let from_path = match (segments.next(), segments.next(), segments.next()) {
+            // Names with spaces or other reserved characters are
+            // percent-encoded in the path, such as `/models/my%20model/predict`.
+            (Some("models"), Some(name), Some("predict")) if segments.next().is_none() => Some(
+                percent_decode(name)
+                    .ok_or_else(|| ClientError::bad_request("invalid model name in the path"))?,
+            ),
+            (Some("models"), _, _) => {
+                return Err(ClientError::not_found(
+                    "expected a path such as /models/<name>/predict",
+                ))
+            }
+            _ => None,
+        };
+        let name = match (from_path.as_deref(), field) {
+            (Some(from_path), Some(field)) if from_path != field => {
+                return Err(ClientError::bad_request(format!(
+                    "the path of the request selects model {}, but its model field selects {}",
+                    from_path, field
+                )))
+            }
+            (Some(name), _) | (None, Some(name)) => name,
+            (None, None) => return Ok(self.models[DEFAULT_MODEL].clone()),
+        };
+        self.models.get(name).cloned().ok_or_else(|| {
+            ClientError::not_found(format!(
+                "unknown model {}, available models: {}",
+                name,
+                self.models.keys().cloned().collect::<Vec<_>>().join(", ")
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use structopt::StructOpt;
+
+    use super::*;
+
+    /// The mapping of MobileNet V2, with a line for the background class.
+    const MOBILENET: LabelMapping = LabelMapping {
+        label_offset: 0,
+        has_background: true,
+    };
+
+    /// Read the labels of a labels file with these contents.
+    fn read_labels(name: &str, contents: &str, mapping: LabelMapping) -> Labels {
+        let file = std::env::temp_dir().join(format!(
+            "wasi-tensorflow-inference-{}-{}.txt",
+            std::process::id(),
+            name
+        ));
+        fs::write(&file, contents).unwrap();
+        let labels = Labels::read(file.to_str().unwrap(), mapping).unwrap();
+        fs::remove_file(&file).unwrap();
+        labels
+    }
+
+    /// Parse the options of the server from command line arguments.
+    fn opts(args: &[&str]) -> Opts {
+        Opts::from_iter(std::iter::once("wasi-tensorflow-inference").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn rejects_skipping_the_background_of_a_model_without_one() {
+        let register = |opts: &Opts| {
+            let input = SelectedInput {
+                name: None,
+                shape: INPUT_SHAPE.to_vec(),
+            };
+            RegisteredModel::new(
+                DEFAULT_MODEL.to_string(),
+                Arc::new(Vec::new()),
+                input,
+                Normalization::ZeroToOne,
+                opts.labels.clone(),
+                LabelMapping::default_model(opts),
+                opts,
+            )
+        };
+        let err = register(&opts(&["--skip-background", "--no-background"]))
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "--skip-background masks the background class, but the default model has none (--no-background)"
+        );
+        register(&opts(&["--skip-background"])).unwrap();
+        register(&opts(&["--no-background"])).unwrap();
+    }
+
+    #[test]
+    fn rejects_unknown_keys_in_the_models_file() {
+        let config =
+            r#"{"model": "flowers.pb", "labels": "flowers.txt", "input_shap": [1, 224, 224, 3]}"#;
+        let err = serde_json::from_str::<ModelConfig>(config).unwrap_err();
+        assert!(
+            err.to_string().contains("unknown field `input_shap`"),
+            "{}",
+            err
+        );
+        serde_json::from_str::<ModelConfig>(r#"{"model": "flowers.pb", "labels": "flowers.txt"}"#)
+            .unwrap();
+    }
+
+    #[test]
+    fn strips_the_byte_order_mark() {
+        let labels = read_labels("bom", "\u{feff}background\ntench\n", MOBILENET);
+        assert_eq!(labels.labels, ["background", "tench"]);
+    }
+
+    #[test]
+    fn strips_carriage_returns_and_trailing_spaces() {
+        let labels = read_labels("crlf", "background\r\ntench \r\ngoldfish\t\r\n", MOBILENET);
+        assert_eq!(labels.labels, ["background", "tench", "goldfish"]);
+        assert_eq!(labels.len(), 3);
+    }
+
+    #[test]
+    fn keeps_the_lines_of_blank_labels() {
+        // A blank line is the label of its class, so that the following
+        // labels stay on the line of their class.
+        let labels = read_labels("blank", "background\n\ngoldfish\n", MOBILENET);
+        assert_eq!(labels.labels, ["background", "", "goldfish"]);
+        assert_eq!(labels.classes(), 3);
+    }
+
+    #[test]
+    fn maps_classes_to_their_line() {
+        let opts = opts(&[]);
+        let labels = read_labels("lines", "background\ntench\ngoldfish\n", MOBILENET);
+        assert_eq!(labels.get(1, &opts).unwrap(), "background");
+        assert_eq!(labels.get(3, &opts).unwrap(), "goldfish");
+
+        // Without a line for the background class, the labels are on the
+        // line before, and the background class keeps its label.
+        let mapping = LabelMapping {
+            label_offset: 1,
+            has_background: true,
+        };
+        let labels = read_labels("offset", "tench\ngoldfish\n", mapping);
+        assert_eq!(labels.get(1, &opts).unwrap(), "background");
+        assert_eq!(labels.get(3, &opts).unwrap(), "goldfish");
+    }
+
+    #[test]
+    fn fails_on_classes_without_a_label() {
+        let labels = read_labels(
+            "missing",
+            "tench\ngoldfish\n",
+            LabelMapping {
+                label_offset: 0,
+                has_background: false,
+            },
+        );
+        let opts = opts(&[]);
+        for class in [0, 3, 1000] {
+            let err = labels.get(class, &opts).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("no label for class {} in {}", class, labels.file)
+            );
+        }
+    }
+
+    #[test]
+    fn labels_classes_without_a_label_with_a_placeholder() {
+        let labels = read_labels(
+            "placeholder",
+            "tench\ngoldfish\n",
+            LabelMapping {
+                label_offset: 0,
+                has_background: false,
+            },
+        );
+        let opts = opts(&["--labels-fallback", "placeholder"]);
+        assert_eq!(labels.get(2, &opts).unwrap(), "goldfish");
+        assert_eq!(labels.get(3, &opts).unwrap(), "class_3");
+        assert_eq!(labels.get(742, &opts).unwrap(), "class_742");
+    }
+}
@@ -0,0 +1,130 @@
+//! Reuse module instances across requests, instead of creating a new
+//! instance, and compiling the model again, for every prediction.
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use wasmtime::{Instance, Module, Trap};
+
+use crate::{
+    create_instance, load_model, metrics::Metrics, models::RegisteredModel, ClientError, Opts,
+};
+
+thread_local! {
+    /// Instances created by the pools on this thread that are not used by
+    /// a request, by pool and model name, with the handle of the model
+    /// compiled in them.
+    static INSTANCES: RefCell<BTreeMap<(usize, String), Vec<Pooled>>> =
+        const { RefCell::new(BTreeMap::new()) };
+}
+
+/// An instance, with the handle of the model compiled in it.
+type Pooled = (Instance, i32);
+
+/// Identifier of the next pool, as the instances of a pool, which are
+/// created with its options, cannot be used by another one, such as
+/// another `InferenceEngine` on the same thread.
+static NEXT_POOL_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Pool of module instances, in which a model is already compiled.
+///
+/// A `Wasmtime::Instance` cannot be safely sent between threads (see
+/// https://github.com/bytecodealliance/wasmtime/issues/793), so each thread
+/// running inferences, such as the workers of the server, keeps its own
+/// instances, for each model. The module itself is compiled once, and shared
+/// by all the threads.
+pub struct InstancePool {
+    id: usize,
+    module: Module,
+    opts: Arc<Opts>,
+    metrics: Arc<Metrics>,
+}
+
+impl InstancePool {
+    pub fn new(module: Module, opts: Arc<Opts>, metrics: Arc<Metrics>) -> Self {
+        InstancePool {
+            id: NEXT_POOL_ID.fetch_add(1, Ordering::Relaxed),
+            module,
+            opts,
+            metrics,
+        }
+    }
+
+    /// Run `f` with an instance of the current thread in which `model` is
+    /// compiled, and the handle of the model, creating the instance if there
+    /// is none left.
+    ///
+    /// The instance is put back in the pool once `f` returns, unless it
+    /// failed with anything other than a client error, such as a trap,
+    /// which may leave the instance in an inconsistent state. An instance
+    /// that trapped is replaced right away by a new one, so that the next
+    /// inference, such as a retry, does not have to create it.
+    pub fn with_instance<T>(
+        &self,
+        model: &RegisteredModel,
+        f: impl FnOnce(&Instance, i32) -> Result<T, anyhow::Error>,
+    ) -> Result<T, anyhow::Error> {
+        let pooled = INSTANCES.with(|instances| {
+            instances
+                .borrow_mut()
+                .get_mut(&(self.id, model.name.clone()))
+                .and_then(|pooled| pooled.pop())
+        });
+        let (instance, handle) = match pooled {
+            Some(pooled) => pooled,
+            None => self.create(model)?,
+        };
+
+        let result = f(&instance, handle);
+        match &result {
+            Err(err) if err.is::<Trap>() => {
+                tracing::error!(model = %model.name, trap = %err, "the module trapped");
+                drop(instance);
+                match self.create(model) {
+                    Ok(replacement) => self.put_back(model, replacement),
+                    Err(err) => tracing::warn!(error = %err, "cannot replace the instance"),
+                }
+            }
+            Err(err) if !err.is::<ClientError>() => {}
+            _ => self.put_back(model, (instance, handle)),
+        }
+        result
+    }
+
+    /// Create an instance, and compile the model in it.
+    fn create(&self, model: &RegisteredModel) -> Result<Pooled, anyhow::Error> {
+        let start = Instant::now();
+        let instance = create_instance(&self.module, &self.opts)?;
+        let handle = load_model(&instance, model, &self.opts)?;
+        self.metrics.instantiation(start.elapsed());
+        Ok((instance, handle))
+    }
+
+    /// Put an instance of the current thread, with the handle of the model
+    /// compiled in it, in the pool.
+    fn put_back(&self, model: &RegisteredModel, pooled: Pooled) {
+        INSTANCES.with(|instances| {
+            instances
+                .borrow_mut()
+                .entry((self.id, model.name.clone()))
+                .or_default()
+                .push(pooled)
+        })
+    }
+}
+
+impl Drop for InstancePool {
+    /// Drop the instances of the pool on the current thread. The ones of
+    /// the other threads are dropped with their thread.
+    fn drop(&mut self) {
+        let _ = INSTANCES
+            .try_with(|instances| instances.borrow_mut().retain(|(id, _), _| *id != self.id));
+    }
+}
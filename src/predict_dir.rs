@@ -0,0 +1,196 @@
+//! Run the inference on all the images in a directory from the command line,
+//! reusing a single module instance for all of them.
+
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use glob::Pattern;
+use serde_json::json;
+use structopt::StructOpt;
+use wasmtime::Module;
+
+use crate::{
+    create_instance, infer_image, load_model, models::RegisteredModel, Opts, PredictParams,
+};
+
+/// Options of the `predict-dir` command.
+#[derive(Debug, StructOpt)]
+pub struct PredictDirOpts {
+    /// Directory containing the images.
+    dir: PathBuf,
+
+    /// Also run the inference on the images in subdirectories.
+    #[structopt(long)]
+    recursive: bool,
+
+    /// Only use the files whose name matches this glob pattern.
+    /// By default, files with a `.jpg` or `.jpeg` extension are used.
+    #[structopt(long)]
+    glob: Option<Pattern>,
+
+    /// Format of the results (jsonl or csv).
+    #[structopt(long, default_value = "jsonl")]
+    format: OutputFormat,
+
+    /// Write the results to a file instead of the standard output.
+    #[structopt(long, short)]
+    output: Option<PathBuf>,
+}
+
+/// Formats in which the results of `predict-dir` can be written.
+#[derive(Debug)]
+enum OutputFormat {
+    JsonLines,
+    Csv,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "jsonl" => Ok(OutputFormat::JsonLines),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err(anyhow::Error::msg(format!(
+                "unknown output format {}, expected jsonl or csv",
+                format
+            ))),
+        }
+    }
+}
+
+/// Run the inference on the images of the directory, writing one result
+/// per image. Images that cannot be classified are reported in the output
+/// and do not stop the command.
+pub fn run(
+    cmd: &PredictDirOpts,
+    opts: &Opts,
+    module: &Module,
+    model: &RegisteredModel,
+) -> Result<(), anyhow::Error> {
+    let mut files = Vec::new();
+    collect_files(&cmd.dir, cmd, &mut files)?;
+    files.sort();
+
+    let mut out: Box<dyn Write> = match &cmd.output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout()),
+    };
+    if let OutputFormat::Csv = cmd.format {
+        writeln!(out, "file,index,label,error")?;
+    }
+
+    let labels = model.labels(opts, &[]);
+    let params = PredictParams::default();
+    let mut instance = create_instance(module, opts)?;
+    let mut handle = load_model(&instance, model, opts)?;
+
+    for file in files {
+        let result = fs::read(&file)
+            .map_err(anyhow::Error::new)
+            .and_then(|img_bytes| infer_image(&instance, model, handle, &img_bytes, opts, &params))
+            .map(|(class, _)| class)
+            .and_then(|class| Ok((class, labels.get(class, opts)?)));
+
+        // Only keep the first line of the error, as traps also contain
+        // the backtrace of the module.
+        let result = result.map_err(|err| {
+            err.to_string()
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .to_string()
+        });
+        if result.is_err() {
+            // The instance might be left in an inconsistent state after
+            // a failed inference, so start over with a new one.
+            instance = create_instance(module, opts)?;
+            handle = load_model(&instance, model, opts)?;
+        }
+
+        write_result(&mut out, &cmd.format, &file, &result)?;
+    }
+
+    out.flush()?;
+    Ok(())
+}
+
+/// Add the files of a directory that should be classified to `files`,
+/// walking the subdirectories if the command is recursive.
+fn collect_files(
+    dir: &Path,
+    cmd: &PredictDirOpts,
+    files: &mut Vec<PathBuf>,
+) -> Result<(), anyhow::Error> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            if cmd.recursive {
+                collect_files(&path, cmd, files)?;
+            }
+            continue;
+        }
+
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let matches = match &cmd.glob {
+            Some(pattern) => pattern.matches(&name),
+            None => {
+                let name = name.to_lowercase();
+                name.ends_with(".jpg") || name.ends_with(".jpeg")
+            }
+        };
+        if matches {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Write the result of the inference on a single file.
+fn write_result(
+    out: &mut dyn Write,
+    format: &OutputFormat,
+    file: &Path,
+    result: &Result<(usize, String), String>,
+) -> Result<(), anyhow::Error> {
+    let file = file.to_string_lossy();
+    match format {
+        OutputFormat::JsonLines => {
+            let line = match result {
+                Ok((index, label)) => json!({ "file": file, "index": index, "label": label }),
+                Err(err) => json!({ "file": file, "error": err }),
+            };
+            writeln!(out, "{}", line)?;
+        }
+        OutputFormat::Csv => {
+            let (index, label, err) = match result {
+                Ok((index, label)) => (index.to_string(), label.as_str(), ""),
+                Err(err) => (String::new(), "", err.as_str()),
+            };
+            writeln!(
+                out,
+                "{},{},{},{}",
+                csv_field(&file),
+                index,
+                csv_field(label),
+                csv_field(err)
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Quote a CSV field if it contains a separator, a quote, or a new line.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
@@ -0,0 +1,122 @@
+//! Serve HTTPS with `--tls-cert` and `--tls-key`, instead of plain HTTP.
+
+use std::{fs::File, io::BufReader, net::SocketAddr, sync::Arc, time::Duration};
+
+use futures::{
+    future,
+    stream::{self, Stream, StreamExt},
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{
+    rustls::{internal::pemfile, NoClientAuth, PrivateKey, ServerConfig},
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+use crate::Opts;
+
+/// Time a client has to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of TLS handshakes in progress before the server stops accepting
+/// connections, so that slow clients do not hold up the others.
+const MAX_HANDSHAKES: usize = 64;
+
+/// Return the TLS configuration of the server, with its certificate and
+/// key, or `None` when serving plain HTTP, which is the default.
+///
+/// The certificate and the key are both PEM files. The certificate file
+/// can contain a chain, starting with the certificate of the server, and
+/// the key can be a PKCS #8 or an RSA key.
+pub fn acceptor(opts: &Opts) -> Result<Option<TlsAcceptor>, anyhow::Error> {
+    let (cert_file, key_file) = match (&opts.tls_cert, &opts.tls_key) {
+        (Some(cert), Some(key)) => (cert, key),
+        (None, None) => return Ok(None),
+        _ => {
+            return Err(anyhow::Error::msg(
+                "--tls-cert and --tls-key must be set together",
+            ))
+        }
+    };
+
+    let invalid = |file: &str, what: &str| {
+        anyhow::Error::msg(format!("{} does not contain a PEM encoded {}", file, what))
+    };
+    let certs =
+        pemfile::certs(&mut open(cert_file)?).map_err(|()| invalid(cert_file, "certificate"))?;
+    if certs.is_empty() {
+        return Err(invalid(cert_file, "certificate"));
+    }
+    let key = private_key(key_file)?.ok_or_else(|| invalid(key_file, "private key"))?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(certs, key)
+        .map_err(|err| anyhow::Error::msg(format!("invalid TLS certificate or key: {}", err)))?;
+    // Clients that negotiate the protocol with ALPN get the one the server
+    // speaks.
+    let protocol = if opts.http2 { "h2" } else { "http/1.1" };
+    config.set_protocols(&[protocol.as_bytes().to_vec()]);
+    Ok(Some(TlsAcceptor::from(Arc::new(config))))
+}
+
+/// Read the first PKCS #8 key of a file, or its first RSA key.
+fn private_key(file: &str) -> Result<Option<PrivateKey>, anyhow::Error> {
+    let invalid = || anyhow::Error::msg(format!("{} is not a valid PEM file", file));
+    let keys = pemfile::pkcs8_private_keys(&mut open(file)?).map_err(|()| invalid())?;
+    if let Some(key) = keys.into_iter().next() {
+        return Ok(Some(key));
+    }
+    let keys = pemfile::rsa_private_keys(&mut open(file)?).map_err(|()| invalid())?;
+    Ok(keys.into_iter().next())
+}
+
+fn open(file: &str) -> Result<BufReader<File>, anyhow::Error> {
+    File::open(file)
+        .map(BufReader::new)
+        .map_err(|err| anyhow::Error::msg(format!("cannot read {}: {}", file, err)))
+}
+
+/// Accept the connections to `addr`, and return those whose TLS handshake
+/// completed, for `hyper::server::accept::from_stream`. Connections that
+/// cannot be accepted, or whose handshake fails, are logged and dropped,
+/// without stopping the server.
+pub fn incoming(
+    addr: &SocketAddr,
+    acceptor: TlsAcceptor,
+) -> Result<impl Stream<Item = Result<TlsStream<TcpStream>, std::io::Error>>, anyhow::Error> {
+    let listener = std::net::TcpListener::bind(addr)
+        .and_then(|listener| {
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener)
+        })
+        .map_err(|err| anyhow::Error::msg(format!("cannot listen on {}: {}", addr, err)))?;
+
+    let connections = stream::unfold(listener, |mut listener| async {
+        let conn = listener.accept().await;
+        Some((conn, listener))
+    });
+    Ok(connections
+        .filter_map(|conn| match conn {
+            Ok((tcp, _)) => future::ready(Some(tcp)),
+            Err(err) => {
+                tracing::warn!(error = %err, "cannot accept connection");
+                future::ready(None)
+            }
+        })
+        .map(move |tcp| tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(tcp)))
+        .buffer_unordered(MAX_HANDSHAKES)
+        .filter_map(|handshake| {
+            future::ready(match handshake {
+                Ok(Ok(tls)) => Some(Ok(tls)),
+                Ok(Err(err)) => {
+                    tracing::info!(error = %err, "TLS handshake failed");
+                    None
+                }
+                Err(_) => {
+                    tracing::info!("TLS handshake timed out");
+                    None
+                }
+            })
+        }))
+}
@@ -0,0 +1,116 @@
+//! Run the inferences on a fixed number of worker threads, instead of the
+//! threads of the runtime, which are left to handle the connections.
+//!
+//! Each worker keeps its own instances of the module, in the pool of its
+//! thread, and takes the predictions from a queue shared by all the
+//! workers. When the queue is full, predictions are rejected instead of
+//! waiting, so that the memory used by the server, with the images of the
+//! waiting requests, stays bounded.
+
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use tokio::sync::oneshot;
+use tracing::Span;
+
+use crate::ClientError;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Seconds clients are asked to wait, with the `Retry-After` header, before
+/// retrying the predictions rejected because the queue is full, which is
+/// about the time a worker takes to run a couple of them.
+const BUSY_RETRY_AFTER: u64 = 1;
+
+/// The worker threads, and the sending end of their queue.
+pub struct Workers {
+    sender: SyncSender<Job>,
+}
+
+impl Workers {
+    /// Start `count` workers, with a queue of up to `queue_size` predictions
+    /// waiting for one of them. Each worker first runs `init`, such as a
+    /// warmup inference, and this returns once all of them have, or with
+    /// the first error of `init`.
+    pub fn start(
+        count: usize,
+        queue_size: usize,
+        init: impl Fn() -> Result<(), anyhow::Error> + Send + Sync + 'static,
+    ) -> Result<Self, anyhow::Error> {
+        if count == 0 {
+            return Err(anyhow::Error::msg("expected at least one worker"));
+        }
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let init = Arc::new(init);
+
+        let (ready, started) = mpsc::channel();
+        for i in 0..count {
+            let receiver = receiver.clone();
+            let init = init.clone();
+            let ready = ready.clone();
+            thread::Builder::new()
+                .name(format!("inference-worker-{}", i))
+                .spawn(move || {
+                    let _ = ready.send(init());
+                    work(&receiver);
+                })?;
+        }
+        for _ in 0..count {
+            started.recv()??;
+        }
+        Ok(Workers { sender })
+    }
+
+    /// Run `f` on one of the workers, in the span of the caller, and return
+    /// its result, or a 503 error, with a `Retry-After` header, when all the
+    /// workers are busy and the queue is full.
+    pub async fn run<T: Send + 'static>(
+        &self,
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> Result<T, anyhow::Error> {
+        let (result, receiver) = oneshot::channel();
+        let span = Span::current();
+        let job = Box::new(move || {
+            let _ = result.send(span.in_scope(f));
+        });
+        match self.sender.try_send(job) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                return Err(ClientError::unavailable(
+                    "all the workers are busy, try again later",
+                    BUSY_RETRY_AFTER,
+                )
+                .into())
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                return Err(anyhow::Error::msg("the workers have stopped"))
+            }
+        }
+        receiver
+            .await
+            .map_err(|_| anyhow::Error::msg("the worker panicked while running the prediction"))
+    }
+}
+
+/// Run the jobs of the queue, until the queue is dropped.
+fn work(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        // The lock is only held while waiting for the next job.
+        let job = receiver.lock().expect("the queue is poisoned").recv();
+        match job {
+            // A job that panics only fails its own request, and the worker
+            // goes on with the next one.
+            Ok(job) => {
+                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            }
+            Err(_) => return,
+        }
+    }
+}
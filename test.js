@@ -4,8 +4,10 @@ const path = require("path");
 
 const module_bytes = fs.readFileSync("./model/optimized-wasi.wasm");
 const model_bytes = fs.readFileSync("./model/mobilenet_v2_1.4_224_frozen.pb");
-const label_bytes = fs.readFileSync("./model/labels.txt", "utf-8");
 const testdata_dir = "./testdata";
+// The module has the labels of MobileNet V2, and writes the label of the
+// predicted class in a buffer of this size.
+const max_label_len = 1024;
 
 const mod = new WebAssembly.Module(module_bytes);
 const wasi = new WASI();
@@ -32,16 +34,23 @@ function getPrediction(model_bytes, img_bytes, instance) {
   var start = new Date();
   var mptr = writeGuestMemory(model_bytes, instance);
   var iptr = writeGuestMemory(img_bytes, instance);
+  var lptr = instance.exports.alloc(max_label_len);
 
-  let pred = instance.exports.infer_from_ptrs(
+  let len = instance.exports.infer_label_from_ptrs(
     mptr,
     model_bytes.length,
     iptr,
-    model_bytes.length
+    img_bytes.length,
+    lptr,
+    max_label_len
   );
   console.log("inference time: ", new Date() - start + " ms");
 
-  return getLabel(pred);
+  if (len < 0 || len > max_label_len) {
+    throw new Error("cannot get predicted label: " + len);
+  }
+  var label = new Uint8Array(instance.exports.memory.buffer, lptr, len);
+  return Buffer.from(label).toString("utf-8");
 }
 
 function writeGuestMemory(bytes, instance) {
@@ -52,13 +61,3 @@ function writeGuestMemory(bytes, instance) {
 
   return ptr;
 }
-
-function getLabel(pred) {
-  var lines = label_bytes.split("\n");
-
-  if (pred > lines.length) {
-    throw new Error("cannot get predicted label");
-  }
-
-  return lines[pred - 1];
-}
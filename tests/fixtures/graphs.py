@@ -0,0 +1,129 @@
+"""Write the TensorFlow graphs used by the integration tests.
+
+The graphs are encoded by hand, so that TensorFlow is not needed to
+regenerate them: `python3 tests/fixtures/graphs.py`.
+"""
+
+import os
+
+
+def varint(n):
+    out = b""
+    while True:
+        byte, n = n & 0x7F, n >> 7
+        if not n:
+            return out + bytes([byte])
+        out += bytes([byte | 0x80])
+
+
+def field(num, payload):
+    """A length-delimited field of a protobuf message."""
+    return varint(num << 3 | 2) + varint(len(payload)) + payload
+
+
+def string(num, value):
+    return field(num, value.encode())
+
+
+def attr(key, value):
+    # NodeDef.attr is a map, whose entries have a key and a value.
+    return field(5, string(1, key) + field(2, value))
+
+
+# AttrValue.type, with DT_FLOAT, DT_INT32, DT_UINT8 or DT_INT8.
+FLOAT = varint(6 << 3) + varint(1)
+INT32 = varint(6 << 3) + varint(3)
+UINT8 = varint(6 << 3) + varint(4)
+INT8 = varint(6 << 3) + varint(6)
+
+
+def shape(dims):
+    # AttrValue.shape, with a TensorShapeProto.Dim for each dimension.
+    return field(7, b"".join(field(2, varint(1 << 3) + varint(d)) for d in dims))
+
+
+def placeholder(name, dims=None, dtype=FLOAT):
+    node = string(1, name) + string(2, "Placeholder") + attr("dtype", dtype)
+    if dims is not None:
+        node += attr("shape", shape(dims))
+    return node
+
+
+def int32_const(name, values):
+    # AttrValue.tensor, with its dtype, its shape and its packed int_val.
+    tensor = (
+        varint(1 << 3)
+        + varint(3)
+        + field(2, field(2, varint(1 << 3) + varint(len(values))))
+        + field(7, b"".join(varint(v) for v in values))
+    )
+    return string(1, name) + string(2, "Const") + attr("dtype", INT32) + attr("value", field(8, tensor))
+
+
+def reshape(name, input, shape, dtype=FLOAT):
+    return (
+        string(1, name)
+        + string(2, "Reshape")
+        + string(3, input)
+        + string(3, shape)
+        + attr("T", dtype)
+        + attr("Tshape", INT32)
+    )
+
+
+def cast(name, input, src, dst):
+    return (
+        string(1, name)
+        + string(2, "Cast")
+        + string(3, input)
+        + attr("SrcT", src)
+        + attr("DstT", dst)
+    )
+
+
+def identity(name, input):
+    return string(1, name) + string(2, "Identity") + string(3, input) + attr("T", FLOAT)
+
+
+def graph(*nodes):
+    # GraphDef.node
+    return b"".join(field(1, node) for node in nodes)
+
+
+GRAPHS = {
+    # Two inputs, so that the input the images are fed to must be selected.
+    "two-inputs.pb": graph(
+        placeholder("image", [1, 4, 6, 3]),
+        placeholder("mask"),
+        identity("scores", "image"),
+        identity("masked", "mask"),
+    ),
+    # A single input, which is not an image, and is reshaped so that the
+    # graph only accepts tensors of 10 values.
+    "vector-input.pb": graph(
+        placeholder("vector", [1, 10]),
+        int32_const("shape", [1, 10]),
+        reshape("scores", "vector", "shape"),
+    ),
+    # A model whose output is the pixel of its input, which tensors of
+    # logits of any sign are sent as.
+    "logits.pb": graph(
+        placeholder("image", [1, 1, 1, 3]),
+        int32_const("shape", [1, 3]),
+        reshape("scores", "image", "shape"),
+    ),
+    # A quantized model, whose `int8` output is the red, green and blue
+    # channels of the image resized to a single pixel.
+    "quantized-output.pb": graph(
+        placeholder("image", [1, 1, 1, 3], UINT8),
+        int32_const("shape", [1, 3]),
+        reshape("pixel", "image", "shape", UINT8),
+        cast("scores", "pixel", UINT8, INT8),
+    ),
+}
+
+if __name__ == "__main__":
+    directory = os.path.dirname(os.path.abspath(__file__))
+    for name, content in GRAPHS.items():
+        with open(os.path.join(directory, name), "wb") as f:
+            f.write(content)
@@ -0,0 +1,13 @@
+
+>
+imagePlaceholder*
+dtype0*
+shape:
+4
+shapeConst*
+dtype0*
+valueB:
+6
+scoresReshapeimageshape*
+T0*
+Tshape0
\ No newline at end of file
@@ -0,0 +1,19 @@
+
+>
+imagePlaceholder*
+dtype0*
+shape:
+4
+shapeConst*
+dtype0*
+valueB:
+5
+pixelReshapeimageshape*
+T0*
+Tshape0
+-
+scoresCastpixel*
+
+SrcT0*
+
+DstT0
\ No newline at end of file
@@ -0,0 +1,14 @@
+
+>
+imagePlaceholder*
+dtype0*
+shape:
+ 
+maskPlaceholder*
+dtype0
+"
+scoresIdentityimage*
+T0
+!
+maskedIdentitymask*
+T0
\ No newline at end of file
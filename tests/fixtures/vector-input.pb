@@ -0,0 +1,16 @@
+
+7
+vectorPlaceholder*
+dtype0*
+shape
+:
+
+4
+shapeConst*
+dtype0*
+valueB:
+
+7
+scoresReshapevectorshape*
+T0*
+Tshape0
\ No newline at end of file
@@ -0,0 +1,232 @@
+//! Run the inference pipeline of the library on known images, with the
+//! bundled MobileNet V2 model, and on the small graphs of `tests/fixtures`,
+//! which are written by `tests/fixtures/graphs.py`.
+//!
+//! The tests use the module in `model/`, so they run the module as it was
+//! last built.
+
+use std::{
+    fs,
+    sync::{Arc, OnceLock},
+};
+
+use structopt::StructOpt;
+use wasi_tensorflow_inference::{read_file_bytes, InferenceEngine, Opts};
+
+const MOBILENET_V2: &str = "model/mobilenet_v2_1.4_224_frozen.pb";
+const LABELS: &str = "model/labels.txt";
+
+const HUSKY: &[u8] = include_bytes!("../testdata/husky.jpeg");
+const GOLDEN_RETRIEVER: &[u8] = include_bytes!("../testdata/golden-retriever.jpeg");
+const WIDE_GOLDEN_RETRIEVER: &[u8] = include_bytes!("../testdata/wide-golden-retriever.jpeg");
+
+/// The engine with MobileNet V2, shared by the tests, since compiling the
+/// module takes a while.
+fn mobilenet() -> &'static InferenceEngine {
+    static ENGINE: OnceLock<InferenceEngine> = OnceLock::new();
+    ENGINE.get_or_init(|| InferenceEngine::new(MOBILENET_V2, LABELS).unwrap())
+}
+
+/// An engine with the default model, and the options of the server given
+/// as command line arguments.
+fn with_args(args: &[&str]) -> InferenceEngine {
+    let opts =
+        Opts::from_iter(std::iter::once("wasi-tensorflow-inference").chain(args.iter().copied()));
+    InferenceEngine::from_opts(Arc::new(opts)).unwrap()
+}
+
+/// Engines with the default model and the options of the server given as
+/// command line arguments, with a class prior that adds the given log-prior
+/// to the score of each class, and 0 to the other ones.
+fn with_prior<const N: usize>(prior: &[(usize, f32)], args: [&[&str]; N]) -> [InferenceEngine; N] {
+    let file = std::env::temp_dir().join(format!(
+        "wasi-tensorflow-inference-{}-prior-{}.txt",
+        std::process::id(),
+        prior
+            .iter()
+            .map(|(class, log_prior)| format!("{}-{}", class, log_prior))
+            .collect::<Vec<_>>()
+            .join("-")
+    ));
+    let mut lines = vec![0.0; 1001];
+    for &(class, log_prior) in prior {
+        lines[class - 1] = log_prior;
+    }
+    let lines: Vec<String> = lines.iter().map(|v| v.to_string()).collect();
+    fs::write(&file, lines.join("\n")).unwrap();
+    let engines =
+        args.map(|args| with_args(&[&["--class-prior", file.to_str().unwrap()], args].concat()));
+    fs::remove_file(&file).unwrap();
+    engines
+}
+
+#[test]
+fn predicts_the_class_of_known_images() {
+    let prediction = mobilenet().predict(HUSKY).unwrap();
+    assert_eq!(prediction.index, 250);
+    assert_eq!(prediction.label, "Eskimo dog, husky");
+
+    let prediction = mobilenet().predict(GOLDEN_RETRIEVER).unwrap();
+    assert_eq!(prediction.index, 209);
+    assert_eq!(prediction.label, "golden retriever");
+    assert!(
+        (prediction.score - 0.727).abs() < 0.01,
+        "unexpected score {}",
+        prediction.score
+    );
+}
+
+#[test]
+fn predicts_the_same_classes_with_simd() {
+    // The module does not use SIMD instructions, but enabling the proposal
+    // must not change how the rest of the module is compiled.
+    let simd = with_args(&["--wasm-simd"]);
+    for image in [HUSKY, GOLDEN_RETRIEVER] {
+        let expected = mobilenet().predict(image).unwrap();
+        let prediction = simd.predict(image).unwrap();
+        assert_eq!(prediction.index, expected.index);
+        assert_eq!(prediction.score, expected.score);
+    }
+}
+
+#[test]
+fn center_crops_wide_images() {
+    // Resizing the whole image squashes the golden retriever in the middle
+    // of the wide image, which is then predicted as an Afghan hound.
+    let prediction = mobilenet().predict(WIDE_GOLDEN_RETRIEVER).unwrap();
+    assert_eq!(prediction.label, "Afghan hound, Afghan");
+
+    let center_crop = with_args(&["--preprocess", "center-crop"]);
+    let prediction = center_crop.predict(WIDE_GOLDEN_RETRIEVER).unwrap();
+    assert_eq!(prediction.index, 209);
+    assert_eq!(prediction.label, "golden retriever");
+}
+
+#[test]
+fn maps_the_classes_after_the_background_class_to_their_labels() {
+    // The first of the 1001 classes of MobileNet V2 is a background class,
+    // which `model/labels.txt` has a line for, so the class of the husky
+    // is on line 250, and on line 249 of a labels file without it.
+    let engine = InferenceEngine::with_label_offset(
+        MOBILENET_V2,
+        "tests/fixtures/labels-without-background.txt",
+        1,
+    )
+    .unwrap();
+    let prediction = engine.predict(HUSKY).unwrap();
+    assert_eq!(prediction.index, 250);
+    assert_eq!(prediction.label, "Eskimo dog, husky");
+}
+
+#[test]
+fn never_predicts_the_background_class_when_skipping_it() {
+    // No image is predicted as the background class, unless a prior
+    // makes it more likely than any other class.
+    let [favored, skipped] = with_prior(&[(1, 100.0)], [&[], &["--skip-background"]]);
+    assert_eq!(favored.predict(HUSKY).unwrap().index, 1);
+    for image in [HUSKY, GOLDEN_RETRIEVER] {
+        let prediction = skipped.predict(image).unwrap();
+        assert_ne!(prediction.index, 1);
+        assert_eq!(prediction.index, mobilenet().predict(image).unwrap().index);
+    }
+}
+
+#[test]
+fn adds_the_class_prior_to_the_scores() {
+    // The golden retriever is about 34 times more likely than a Sussex
+    // spaniel, the class of line 222, which takes a log-prior of more than
+    // ln(34) to be predicted instead.
+    let [enough] = with_prior(&[(222, 4.0)], [&[]]);
+    let prediction = enough.predict(GOLDEN_RETRIEVER).unwrap();
+    assert_eq!(prediction.index, 222);
+    assert_eq!(prediction.label, "Sussex spaniel");
+
+    let [not_enough] = with_prior(&[(222, 3.0)], [&[]]);
+    assert_eq!(not_enough.predict(GOLDEN_RETRIEVER).unwrap().index, 209);
+}
+
+#[test]
+fn predictions_are_stable() {
+    let first = mobilenet().predict(HUSKY).unwrap();
+    let second = mobilenet().predict(HUSKY).unwrap();
+    assert_eq!(first.index, second.index);
+    assert_eq!(first.score, second.score);
+}
+
+#[test]
+fn rejects_data_that_is_not_an_image() {
+    let err = mobilenet().predict(b"not an image").unwrap_err();
+    assert_eq!(err.to_string(), "cannot decode the image");
+
+    // A JPEG cut in the middle cannot be decoded either.
+    let err = mobilenet().predict(&HUSKY[..HUSKY.len() / 2]).unwrap_err();
+    assert_eq!(err.to_string(), "cannot decode the image");
+}
+
+#[test]
+fn rejects_images_too_large_to_decode() {
+    // The header of a JPEG can claim any dimensions, whatever the size of
+    // the file, so the image is rejected before its pixels are decoded.
+    let mut image = HUSKY.to_vec();
+    let sof = image
+        .windows(2)
+        .position(|marker| marker == [0xFF, 0xC0] || marker == [0xFF, 0xC2])
+        .unwrap();
+    image[sof + 5..sof + 9].copy_from_slice(&[0x4E, 0x20, 0x4E, 0x20]);
+    let err = mobilenet().predict(&image).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "the image is larger than 10000x10000 pixels"
+    );
+}
+
+#[test]
+fn requires_a_single_input() {
+    let err = InferenceEngine::new("tests/fixtures/two-inputs.pb", LABELS)
+        .err()
+        .unwrap();
+    assert_eq!(
+        err.to_string(),
+        "model tests/fixtures/two-inputs.pb has 2 inputs, select one by its name (see the readme): \
+         image (f32, [1, 4, 6, 3]), mask (f32, unknown shape)"
+    );
+}
+
+#[test]
+fn rejects_models_whose_input_is_not_an_image() {
+    // The graph does not give the shape of an image, so the images are
+    // resized to 224 x 224, which the input of the graph does not accept,
+    // and the classes of the model, which the labels are checked against,
+    // cannot be computed.
+    let err = InferenceEngine::new("tests/fixtures/vector-input.pb", LABELS)
+        .err()
+        .unwrap();
+    assert_eq!(
+        err.to_string(),
+        "cannot get the number of classes of model tests/fixtures/vector-input.pb"
+    );
+}
+
+#[test]
+fn reads_whole_files() {
+    // The model is about 24 MB, which can take several reads.
+    let bytes = read_file_bytes(MOBILENET_V2).unwrap();
+    assert_eq!(
+        bytes.len() as u64,
+        fs::metadata(MOBILENET_V2).unwrap().len()
+    );
+    assert_eq!(bytes, fs::read(MOBILENET_V2).unwrap());
+}
+
+#[test]
+fn fails_on_missing_files() {
+    let err = InferenceEngine::new("tests/fixtures/missing.pb", LABELS)
+        .err()
+        .unwrap();
+    assert!(
+        err.to_string()
+            .starts_with("missing files: tests/fixtures/missing.pb"),
+        "unexpected error {}",
+        err
+    );
+}
@@ -0,0 +1,484 @@
+//! Send requests to the routes of the server, as `main` does for the
+//! requests it accepts, with the bundled MobileNet V2 model.
+//!
+//! The tests use the module in `model/`, so they run the module as it was
+//! last built.
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, OnceLock},
+};
+
+use hyper::{
+    body,
+    header::{HeaderValue, CONTENT_TYPE},
+    http::response::Parts,
+    Body, Request, StatusCode,
+};
+use serde::{de, Deserialize, Deserializer};
+use structopt::StructOpt;
+use wasi_tensorflow_inference::{load_state, route, Opts, State};
+
+const HUSKY: &[u8] = include_bytes!("../testdata/husky.jpeg");
+
+/// Load the state of the server, with its options given as command line
+/// arguments, without the warmup inferences.
+fn state(args: &[&str]) -> Arc<OnceLock<State>> {
+    let args = ["wasi-tensorflow-inference", "--no-warmup"]
+        .iter()
+        .chain(args)
+        .copied();
+    let state = load_state(Arc::new(Opts::from_iter(args))).unwrap();
+    Arc::new(OnceLock::from(state))
+}
+
+/// Return the path of a file in the temporary directory.
+fn temp_file(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "wasi-tensorflow-inference-{}-{}",
+        std::process::id(),
+        name
+    ))
+}
+
+/// The state of the server with its default options, shared by the tests,
+/// since compiling the module takes a while.
+fn server() -> Arc<OnceLock<State>> {
+    static STATE: OnceLock<Arc<OnceLock<State>>> = OnceLock::new();
+    STATE.get_or_init(|| state(&[])).clone()
+}
+
+/// Send a `POST` request to `uri`, and return the response, with its body.
+async fn post(
+    state: Arc<OnceLock<State>>,
+    uri: &str,
+    content_type: &str,
+    body: impl Into<Body>,
+) -> (Parts, Vec<u8>) {
+    let req = Request::post(uri)
+        .header(CONTENT_TYPE, content_type)
+        .body(body.into())
+        .unwrap();
+    let (parts, body) = route(req, state).await.unwrap().into_parts();
+    (parts, body::to_bytes(body).await.unwrap().to_vec())
+}
+
+#[tokio::test]
+async fn returns_the_class_index_as_a_raw_integer() {
+    let (res, body) = post(server(), "/?format=raw-int", "image/jpeg", HUSKY).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(
+        res.headers.get(CONTENT_TYPE),
+        Some(&HeaderValue::from_static("application/octet-stream"))
+    );
+    // The body is only the index of the husky, as a little-endian u32.
+    assert_eq!(body.len(), 4);
+    assert_eq!(
+        u32::from_le_bytes([body[0], body[1], body[2], body[3]]),
+        250
+    );
+}
+
+/// Boundary of the `multipart/form-data` bodies of the tests.
+const BOUNDARY: &str = "wasi-tensorflow-inference-boundary";
+
+/// Return a `multipart/form-data` body with a part for each field, given
+/// by its name, its file name, if it is a file, and its contents.
+fn form(fields: &[(&str, Option<&str>, &[u8])]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (name, file_name, contents) in fields {
+        body.extend_from_slice(format!("--{}\r\n", BOUNDARY).as_bytes());
+        let disposition = match file_name {
+            Some(file_name) => format!("name=\"{}\"; filename=\"{}\"", name, file_name),
+            None => format!("name=\"{}\"", name),
+        };
+        body.extend_from_slice(
+            format!("Content-Disposition: form-data; {}\r\n\r\n", disposition).as_bytes(),
+        );
+        body.extend_from_slice(contents);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+    body
+}
+
+/// Send a form to `uri`, and return the response, with its body.
+async fn post_form(uri: &str, fields: &[(&str, Option<&str>, &[u8])]) -> (Parts, Vec<u8>) {
+    let content_type = format!("multipart/form-data; boundary={}", BOUNDARY);
+    post(server(), uri, &content_type, form(fields)).await
+}
+
+#[tokio::test]
+async fn responds_to_a_form_with_a_single_image_as_to_the_image() {
+    let fields = [("file", Some("husky.jpeg"), HUSKY)];
+    let (res, body) = post_form("/?topk=2", &fields).await;
+    assert_eq!(res.status, StatusCode::OK);
+    let (_, expected) = post(server(), "/?topk=2", "image/jpeg", HUSKY).await;
+    assert_eq!(
+        String::from_utf8(body).unwrap(),
+        String::from_utf8(expected).unwrap()
+    );
+
+    let (res, body) = post_form("/?format=raw-int", &fields).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(body, 250u32.to_le_bytes());
+
+    // Several images are still a batch, with a line for each of them.
+    let fields = [
+        ("file", Some("husky.jpeg"), HUSKY),
+        ("file", Some("readme.md"), &b"# readme"[..]),
+    ];
+    let (res, body) = post_form("/", &fields).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(
+        String::from_utf8(body).unwrap(),
+        "Eskimo dog, husky\nerror: cannot decode the image"
+    );
+    let (res, _) = post_form("/?topk=2", &fields).await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn ignores_the_fields_of_a_form_that_are_not_images() {
+    // Such as the submit button of a browser form, or its CSRF token, which
+    // do not count against `--max-batch-size` either.
+    let (res, body) = post_form(
+        "/",
+        &[
+            ("csrf_token", None, &b"8f2a1c"[..]),
+            ("file", Some("husky.jpeg"), HUSKY),
+            ("submit", None, &b"Classify"[..]),
+        ],
+    )
+    .await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(String::from_utf8(body).unwrap(), "Eskimo dog, husky");
+
+    let (res, body) = post_form("/", &[("submit", None, &b"Classify"[..])]).await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+    assert_eq!(
+        String::from_utf8(body).unwrap(),
+        "expected at least one image"
+    );
+}
+
+/// The probabilities of a response with `?full=true`, by label, in the
+/// order of its JSON object.
+struct Probabilities(Vec<(String, f32)>);
+
+impl<'de> Deserialize<'de> for Probabilities {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = Probabilities;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a map of labels to probabilities")
+            }
+
+            fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut probabilities = Vec::new();
+                while let Some(entry) = map.next_entry()? {
+                    probabilities.push(entry);
+                }
+                Ok(Probabilities(probabilities))
+            }
+        }
+
+        deserializer.deserialize_map(Visitor)
+    }
+}
+
+#[derive(Deserialize)]
+struct FullResponse {
+    label: String,
+    probabilities: Probabilities,
+}
+
+/// Post the husky with `?full=true`, and return the predicted label and the
+/// probabilities of the response.
+async fn full_probabilities(state: Arc<OnceLock<State>>) -> (String, Vec<(String, f32)>) {
+    let (res, body) = post(state, "/?full=true", "image/jpeg", HUSKY).await;
+    assert_eq!(res.status, StatusCode::OK);
+    let res: FullResponse = serde_json::from_slice(&body).unwrap();
+    (res.label, res.probabilities.0)
+}
+
+#[tokio::test]
+async fn returns_the_probability_of_every_class() {
+    let (label, probabilities) = full_probabilities(server()).await;
+    assert_eq!(probabilities.len(), 1001);
+    assert_eq!(probabilities[0].0, label);
+    assert_eq!(label, "Eskimo dog, husky");
+    assert!(probabilities.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+    let sum: f32 = probabilities.iter().map(|(_, p)| p).sum();
+    assert!((sum - 1.0).abs() < 1e-3, "{}", sum);
+    assert!(probabilities.iter().any(|(label, _)| label == "background"));
+
+    // Without the labels check, the classes are still all returned, and the
+    // background class, which is never predicted, is left out.
+    let state = state(&["--labels-check", "off", "--skip-background"]);
+    let (_, probabilities) = full_probabilities(state).await;
+    assert_eq!(probabilities.len(), 1000);
+    assert!(probabilities.iter().all(|(label, _)| label != "background"));
+}
+
+#[tokio::test]
+async fn describes_the_graph_of_the_models_with_model_stats() {
+    let graph = |state: Arc<OnceLock<State>>| async move {
+        let req = Request::get("/models").body(Body::empty()).unwrap();
+        let (res, body) = route(req, state).await.unwrap().into_parts();
+        assert_eq!(res.status, StatusCode::OK);
+        let body: serde_json::Value =
+            serde_json::from_slice(&body::to_bytes(body).await.unwrap()).unwrap();
+        body["models"][0]["graph"].clone()
+    };
+    assert_eq!(graph(server()).await, serde_json::Value::Null);
+
+    let graph = graph(state(&["--model-stats"])).await;
+    for field in [
+        "nodes",
+        "operators",
+        "optimized_nodes",
+        "optimized_operators",
+    ] {
+        assert!(graph[field].as_u64().unwrap() > 0, "{}: {}", field, graph);
+    }
+    assert!(
+        graph["optimized_nodes"].as_u64() < graph["nodes"].as_u64(),
+        "{}",
+        graph
+    );
+}
+
+#[tokio::test]
+async fn rejects_bodies_that_are_not_images() {
+    let html = "<html><body>502 Bad Gateway</body></html>";
+    for body in [
+        &b"\x00\x01garbage\xff"[..],
+        html.as_bytes(),
+        &HUSKY[..HUSKY.len() / 2],
+    ] {
+        let (res, body) = post(server(), "/", "image/jpeg", body.to_vec()).await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST);
+        assert_eq!(body, b"cannot decode the image");
+    }
+}
+
+#[tokio::test]
+async fn rejects_empty_bodies() {
+    for content_type in ["image/jpeg", "text/plain"] {
+        let (res, body) = post(server(), "/", content_type, Body::empty()).await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST, "{}", content_type);
+        assert_eq!(
+            body,
+            b"the request body is empty, expected an image or an image URL"
+        );
+    }
+}
+
+#[tokio::test]
+async fn rejects_urls_that_are_not_utf8() {
+    // Such as an image sent with the default content type of curl.
+    let (res, body) = post(server(), "/", "application/x-www-form-urlencoded", HUSKY).await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+    assert_eq!(
+        String::from_utf8(body).unwrap(),
+        "the request body must be an image URL, or an image with an image content type such as image/jpeg"
+    );
+}
+
+#[tokio::test]
+async fn rejects_tensors_that_are_not_finite() {
+    for value in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+        // A single value is enough for the tensor to be rejected.
+        let mut tensor = vec![0.5f32; 224 * 224 * 3];
+        tensor[1000] = value;
+        let tensor: Vec<u8> = tensor.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let req = Request::post("/?topk=5")
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .header("X-Input-Shape", "1,224,224,3")
+            .body(Body::from(tensor))
+            .unwrap();
+        let (res, body) = route(req, server()).await.unwrap().into_parts();
+        assert_eq!(res.status, StatusCode::BAD_REQUEST, "{}", value);
+        assert_eq!(
+            body::to_bytes(body).await.unwrap(),
+            "the tensor does not match the input of the model, which must be finite f32 values"
+        );
+    }
+}
+
+#[tokio::test]
+async fn dequantizes_the_output_of_quantized_models() {
+    let labels = temp_file("rgb.txt");
+    let config = temp_file("quantized.json");
+    std::fs::write(&labels, "red\ngreen\nblue\n").unwrap();
+    std::fs::write(
+        &config,
+        format!(
+            r#"{{"quantized": {{"model": "tests/fixtures/quantized-output.pb", "labels": {:?},
+                "output_quantization": "0.5,-125", "output": "probabilities"}}}}"#,
+            labels.to_str().unwrap()
+        ),
+    )
+    .unwrap();
+    let state = state(&["--models", config.to_str().unwrap()]);
+    std::fs::remove_file(&labels).unwrap();
+    std::fs::remove_file(&config).unwrap();
+
+    // The raw output is the quantized values, converted to floats.
+    let path = "/models/quantized/predict";
+    let (_, raw) = post(
+        state.clone(),
+        &format!("{}?format=raw-f32", path),
+        "image/jpeg",
+        HUSKY,
+    )
+    .await;
+    let quantized: Vec<f32> = raw
+        .chunks(4)
+        .map(|v| f32::from_le_bytes([v[0], v[1], v[2], v[3]]))
+        .collect();
+    let dequantized: Vec<f32> = quantized.iter().map(|q| (q + 125.0) * 0.5).collect();
+    let sum: f32 = dequantized.iter().sum();
+
+    let (_, body) = post(state, &format!("{}?topk=3", path), "image/jpeg", HUSKY).await;
+    let body = String::from_utf8(body).unwrap();
+    for line in body.lines() {
+        let (label, probability) = line.split_once(": ").unwrap();
+        let class = ["red", "green", "blue"]
+            .iter()
+            .position(|l| *l == label)
+            .unwrap();
+        let expected = dequantized[class] / sum;
+        let probability: f32 = probability.parse().unwrap();
+        assert!(
+            (probability - expected).abs() < 1e-4,
+            "{} instead of {} for {} in {}",
+            probability,
+            expected,
+            label,
+            body
+        );
+    }
+}
+
+#[tokio::test]
+async fn takes_the_output_of_models_as_logits() {
+    let labels = temp_file("logits-rgb.txt");
+    let config = temp_file("logits.json");
+    std::fs::write(&labels, "red\ngreen\nblue\n").unwrap();
+    std::fs::write(
+        &config,
+        format!(
+            r#"{{"logits": {{"model": "tests/fixtures/logits.pb", "labels": {:?}}}}}"#,
+            labels.to_str().unwrap()
+        ),
+    )
+    .unwrap();
+    let state = state(&["--models", config.to_str().unwrap()]);
+    std::fs::remove_file(&labels).unwrap();
+    std::fs::remove_file(&config).unwrap();
+
+    // The output of the graph is the tensor, whose values are negative.
+    let logits = [-2.0f32, -0.5, -3.0];
+    let tensor: Vec<u8> = logits.iter().flat_map(|v| v.to_le_bytes()).collect();
+    let req = Request::post("/models/logits/predict?topk=3")
+        .header(CONTENT_TYPE, "application/octet-stream")
+        .header("X-Input-Shape", "1,1,1,3")
+        .body(Body::from(tensor))
+        .unwrap();
+    let (res, body) = route(req, state).await.unwrap().into_parts();
+    assert_eq!(res.status, StatusCode::OK);
+    let body = String::from_utf8(body::to_bytes(body).await.unwrap().to_vec()).unwrap();
+
+    let sum: f32 = logits.iter().map(|logit| logit.exp()).sum();
+    let lines: Vec<&str> = body.lines().collect();
+    assert_eq!(lines.len(), 3, "{}", body);
+    assert!(lines[0].starts_with("green: "), "{}", body);
+    for line in lines {
+        let (label, probability) = line.split_once(": ").unwrap();
+        let class = ["red", "green", "blue"]
+            .iter()
+            .position(|l| *l == label)
+            .unwrap();
+        let expected = logits[class].exp() / sum;
+        let probability: f32 = probability.parse().unwrap();
+        assert!(
+            (probability - expected).abs() < 1e-4,
+            "{} instead of {} for {} in {}",
+            probability,
+            expected,
+            label,
+            body
+        );
+    }
+}
+
+#[tokio::test]
+async fn selects_the_model_from_the_path() {
+    // The same graph, with other labels.
+    let (labels, config) = (temp_file("numbered.txt"), temp_file("models.json"));
+    let numbered: Vec<String> = (1..=1001).map(|class| format!("class {}", class)).collect();
+    std::fs::write(&labels, numbered.join("\n")).unwrap();
+    std::fs::write(
+        &config,
+        format!(
+            r#"{{"numbered": {{"model": "model/mobilenet_v2_1.4_224_frozen.pb", "labels": {:?}}}}}"#,
+            labels.to_str().unwrap()
+        ),
+    )
+    .unwrap();
+    let state = state(&["--models", config.to_str().unwrap()]);
+    std::fs::remove_file(&labels).unwrap();
+    std::fs::remove_file(&config).unwrap();
+
+    for (path, label) in [
+        ("/", "Eskimo dog, husky"),
+        ("/models/mobilenet/predict", "Eskimo dog, husky"),
+        ("/models/numbered/predict", "class 250"),
+    ] {
+        let (res, body) = post(state.clone(), path, "image/jpeg", HUSKY).await;
+        assert_eq!(res.status, StatusCode::OK, "{}", path);
+        assert_eq!(String::from_utf8(body).unwrap(), label, "{}", path);
+    }
+
+    let (res, body) = post(state, "/models/flowers/predict", "image/jpeg", HUSKY).await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    assert_eq!(
+        String::from_utf8(body).unwrap(),
+        "unknown model flowers, available models: mobilenet, numbered"
+    );
+}
+
+#[tokio::test]
+async fn responds_with_a_500_when_the_model_cannot_be_loaded() {
+    // The input of the graph is not an image, so the images are resized to
+    // 224 x 224, which the graph does not accept. Without the check of the
+    // labels, the model is only loaded by the requests.
+    let (labels, config) = (temp_file("vector.txt"), temp_file("vector.json"));
+    let numbered: Vec<String> = (1..=10).map(|class| format!("class {}", class)).collect();
+    std::fs::write(&labels, numbered.join("\n")).unwrap();
+    std::fs::write(
+        &config,
+        format!(
+            r#"{{"vector": {{"model": "tests/fixtures/vector-input.pb", "labels": {:?}}}}}"#,
+            labels.to_str().unwrap()
+        ),
+    )
+    .unwrap();
+    let state = state(&[
+        "--models",
+        config.to_str().unwrap(),
+        "--labels-check",
+        "off",
+    ]);
+    std::fs::remove_file(&labels).unwrap();
+    std::fs::remove_file(&config).unwrap();
+
+    let (res, body) = post(state, "/models/vector/predict", "image/jpeg", HUSKY).await;
+    assert_eq!(res.status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(String::from_utf8(body).unwrap(), "cannot load model vector");
+}